@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(address) = std::str::from_utf8(data) {
+        // Every accepted format normalizes to an address that decodes to itself
+        if let Ok(decoded) = sovrin_signing::decode_payment_address(address) {
+            let again = sovrin_signing::decode_payment_address(&decoded.address).unwrap();
+            assert_eq!(again.verkey, decoded.verkey);
+        }
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sovrin_signing::Challenge;
+
+fuzz_target!(|data: &[u8]| {
+    // Anything that parses must encode back to the same bytes
+    if let Ok(challenge) = Challenge::parse(data) {
+        assert_eq!(challenge.to_bytes().unwrap(), data);
+    }
+});
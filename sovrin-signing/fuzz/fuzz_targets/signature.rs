@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sovrin_signing::{MessageFormat, Scheme, SignatureEncoding};
+
+const VERKEY: [u8; 32] = [
+    0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64, 0x07, 0x3a,
+    0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a,
+];
+
+fuzz_target!(|data: &[u8]| {
+    let signature = String::from_utf8_lossy(data);
+    for &encoding in &[SignatureEncoding::Base64Url, SignatureEncoding::Base64, SignatureEncoding::Hex] {
+        for &message in &[MessageFormat::PrefixedSha256, MessageFormat::Raw] {
+            let scheme = Scheme { message, encoding };
+            let _ = scheme.decode_signature(&signature);
+            let _ = sovrin_signing::verify_challenge(&VERKEY, data, &signature, scheme);
+        }
+    }
+});
@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+pub const PAY_SOV: &str = "pay:sov:";
+pub const DID_SOV: &str = "did:sov:";
+pub const VERKEY_LENGTH: usize = 32;
+const DID_LENGTH: usize = 16;
+
+/// How a wallet wrote its payment address
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum AddressFormat {
+    /// `pay:sov:` and the base58check encoded verkey, the canonical form
+    #[serde(rename = "pay-sov")]
+    PaySov,
+    /// `did:sov:` and the full base58 verkey
+    #[serde(rename = "did-sov-verkey")]
+    DidSovVerkey,
+    /// A bare base58 verkey
+    #[serde(rename = "raw-verkey")]
+    RawVerkey,
+    /// A DID and an abbreviated verkey e.g. `did:sov:<did>~<abbr>`. The DID
+    /// is the first 16 bytes of the verkey and the abbreviation the rest.
+    #[serde(rename = "abbreviated-verkey")]
+    AbbreviatedVerkey,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PaymentAddress {
+    /// Always the canonical `pay:sov:` form
+    pub address: String,
+    pub verkey: [u8; VERKEY_LENGTH],
+    pub format: AddressFormat,
+}
+
+impl PaymentAddress {
+    pub fn from_verkey(verkey: [u8; VERKEY_LENGTH], format: AddressFormat) -> Self {
+        Self {
+            address: encode(&verkey),
+            verkey,
+            format,
+        }
+    }
+}
+
+/// The canonical `pay:sov:` address of an ed25519 verkey
+pub fn encode(verkey: &[u8; VERKEY_LENGTH]) -> String {
+    format!("{}{}", PAY_SOV, bs58::encode(&verkey[..]).with_check().into_string())
+}
+
+/// Accept any of the encodings in `AddressFormat` and normalize it
+pub fn parse(address: &str) -> Result<PaymentAddress, String> {
+    let address = address.trim();
+    if let Some(encoded) = address.strip_prefix(PAY_SOV) {
+        let verkey = bs58::decode(encoded)
+            .with_check(None)
+            .into_vec()
+            .map_err(|_| "Invalid address".to_string())?;
+        return Ok(PaymentAddress::from_verkey(to_verkey(&verkey)?, AddressFormat::PaySov));
+    }
+
+    let (unqualified, qualified) = match address.strip_prefix(DID_SOV) {
+        Some(a) => (a, true),
+        None => (address, false),
+    };
+    if let Some(split) = unqualified.find('~') {
+        let did = decode_base58(&unqualified[..split])?;
+        let abbreviation = decode_base58(&unqualified[(split + 1)..])?;
+        if did.len() != DID_LENGTH || abbreviation.len() != VERKEY_LENGTH - DID_LENGTH {
+            return Err("Invalid abbreviated verkey".to_string());
+        }
+        let mut verkey = did;
+        verkey.extend_from_slice(&abbreviation);
+        return Ok(PaymentAddress::from_verkey(to_verkey(&verkey)?, AddressFormat::AbbreviatedVerkey));
+    }
+    let verkey = to_verkey(&decode_base58(unqualified)?)?;
+    let format = if qualified {
+        AddressFormat::DidSovVerkey
+    } else {
+        AddressFormat::RawVerkey
+    };
+    Ok(PaymentAddress::from_verkey(verkey, format))
+}
+
+fn decode_base58(value: &str) -> Result<Vec<u8>, String> {
+    bs58::decode(value)
+        .into_vec()
+        .map_err(|_| "Unexpected address type".to_string())
+}
+
+fn to_verkey(bytes: &[u8]) -> Result<[u8; VERKEY_LENGTH], String> {
+    if bytes.len() != VERKEY_LENGTH {
+        return Err("Address is not an ed25519 verkey".to_string());
+    }
+    Ok(*array_ref!(bytes, 0, VERKEY_LENGTH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_normalize_to_pay_sov() {
+        let verkey = [7u8; VERKEY_LENGTH];
+        let canonical = PaymentAddress::from_verkey(verkey, AddressFormat::PaySov);
+        let raw = bs58::encode(&verkey[..]).into_string();
+        let did = bs58::encode(&verkey[..DID_LENGTH]).into_string();
+        let abbreviation = bs58::encode(&verkey[DID_LENGTH..]).into_string();
+
+        for (input, format) in vec![
+            (canonical.address.clone(), AddressFormat::PaySov),
+            (format!("{}{}", DID_SOV, raw), AddressFormat::DidSovVerkey),
+            (raw, AddressFormat::RawVerkey),
+            (format!("{}~{}", did, abbreviation), AddressFormat::AbbreviatedVerkey),
+            (format!("{}{}~{}", DID_SOV, did, abbreviation), AddressFormat::AbbreviatedVerkey),
+        ] {
+            let parsed = parse(&input).unwrap();
+            assert_eq!(parsed.address, canonical.address);
+            assert_eq!(parsed.format, format);
+        }
+        assert!(parse("pay:sov:notbase58check").is_err());
+        assert!(parse(&did).is_err());
+    }
+}
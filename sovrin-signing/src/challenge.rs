@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+pub const CHALLENGE_VERSION: u8 = 1;
+pub const KEY_ID_LENGTH: usize = 4;
+pub const TIMESTAMP_LENGTH: usize = 8;
+pub const NONCE_LENGTH: usize = 32;
+/// An HMAC-SHA256 tag
+pub const HMAC_TAG_LENGTH: usize = 32;
+/// An ed25519 signature
+pub const ED25519_TAG_LENGTH: usize = 64;
+const FIXED_LENGTH: usize = 1 + KEY_ID_LENGTH + TIMESTAMP_LENGTH + NONCE_LENGTH;
+
+/// What the holder of the payment address is agreeing to by signing
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Purpose {
+    #[serde(rename = "prove-address")]
+    ProveAddress,
+    #[serde(rename = "authorize-order")]
+    AuthorizeOrder,
+}
+
+impl Purpose {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Purpose::ProveAddress => "prove-address",
+            Purpose::AuthorizeOrder => "authorize-order",
+        }
+    }
+}
+
+impl Default for Purpose {
+    fn default() -> Self {
+        Purpose::ProveAddress
+    }
+}
+
+impl FromStr for Purpose {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prove-address" => Ok(Purpose::ProveAddress),
+            "authorize-order" => Ok(Purpose::AuthorizeOrder),
+            _ => Err(format!("Unknown challenge purpose: {}", s)),
+        }
+    }
+}
+
+/// Why challenge bytes could not be decoded
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChallengeError {
+    /// Shorter than the fixed size header
+    TooShort(usize),
+    UnsupportedVersion(u8),
+    /// A length prefixed field runs past the end
+    Truncated(&'static str),
+    InvalidUtf8(&'static str),
+    /// A field is too long to be length prefixed with one byte
+    FieldTooLong(&'static str),
+    UnknownPurpose(String),
+    /// The tag is neither an HMAC nor an ed25519 signature
+    InvalidTagLength(usize),
+}
+
+impl fmt::Display for ChallengeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChallengeError::TooShort(n) => write!(f, "Invalid challenge: {} bytes is too short", n),
+            ChallengeError::UnsupportedVersion(v) => write!(f, "Unsupported challenge version {}", v),
+            ChallengeError::Truncated(field) => write!(f, "Invalid challenge: {} is truncated", field),
+            ChallengeError::InvalidUtf8(field) => write!(f, "Invalid challenge: {} is not utf-8", field),
+            ChallengeError::FieldTooLong(field) => write!(f, "Challenge {} is too long", field),
+            ChallengeError::UnknownPurpose(p) => write!(f, "Unknown challenge purpose: {}", p),
+            ChallengeError::InvalidTagLength(n) => write!(f, "Invalid challenge: {} byte tag", n),
+        }
+    }
+}
+
+impl std::error::Error for ChallengeError {}
+
+/// A challenge for a payment address holder to sign. Encoded as
+///
+/// `version || key id || timestamp || nonce || domain || purpose || order id || tag`
+///
+/// where the strings are each prefixed with a one byte length, an empty
+/// order id means none, and the tag is an HMAC or ed25519 signature over
+/// everything before it. Binding the domain and purpose means a signature
+/// made for one site or action cannot be replayed against another.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Challenge {
+    pub version: u8,
+    pub key_id: [u8; KEY_ID_LENGTH],
+    pub issued_at: u64,
+    pub nonce: [u8; NONCE_LENGTH],
+    pub domain: String,
+    pub purpose: Purpose,
+    pub order_id: Option<String>,
+    /// Empty until signed
+    pub tag: Vec<u8>,
+}
+
+impl Challenge {
+    /// The bytes covered by the tag
+    pub fn unsigned_bytes(&self) -> Result<Vec<u8>, ChallengeError> {
+        let mut bytes = vec![self.version];
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(&self.issued_at.to_be_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        let fields = [
+            ("domain", self.domain.as_str()),
+            ("purpose", self.purpose.as_str()),
+            ("order id", self.order_id.as_deref().unwrap_or("")),
+        ];
+        for &(name, value) in &fields {
+            if value.len() > u8::max_value() as usize {
+                return Err(ChallengeError::FieldTooLong(name));
+            }
+            bytes.push(value.len() as u8);
+            bytes.extend_from_slice(value.as_bytes());
+        }
+        Ok(bytes)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ChallengeError> {
+        let mut bytes = self.unsigned_bytes()?;
+        bytes.extend_from_slice(&self.tag);
+        Ok(bytes)
+    }
+
+    /// Decode signed challenge bytes, checking every length along the way
+    pub fn parse(bytes: &[u8]) -> Result<Self, ChallengeError> {
+        if bytes.len() < FIXED_LENGTH {
+            return Err(ChallengeError::TooShort(bytes.len()));
+        }
+        if bytes[0] != CHALLENGE_VERSION {
+            return Err(ChallengeError::UnsupportedVersion(bytes[0]));
+        }
+        let mut rest = &bytes[FIXED_LENGTH..];
+        let domain = take_field(&mut rest, "domain")?;
+        let purpose = take_field(&mut rest, "purpose")?;
+        let order_id = take_field(&mut rest, "order id")?;
+        if rest.len() != HMAC_TAG_LENGTH && rest.len() != ED25519_TAG_LENGTH {
+            return Err(ChallengeError::InvalidTagLength(rest.len()));
+        }
+        Ok(Self {
+            version: bytes[0],
+            key_id: *array_ref!(bytes, 1, KEY_ID_LENGTH),
+            issued_at: u64::from_be_bytes(*array_ref!(bytes, 1 + KEY_ID_LENGTH, TIMESTAMP_LENGTH)),
+            nonce: *array_ref!(bytes, 1 + KEY_ID_LENGTH + TIMESTAMP_LENGTH, NONCE_LENGTH),
+            domain,
+            purpose: purpose.parse().map_err(|_| ChallengeError::UnknownPurpose(purpose))?,
+            order_id: Some(order_id).filter(|o| !o.is_empty()),
+            tag: rest.to_vec(),
+        })
+    }
+}
+
+fn take_field(rest: &mut &[u8], name: &'static str) -> Result<String, ChallengeError> {
+    let (&length, tail) = rest.split_first().ok_or(ChallengeError::Truncated(name))?;
+    if tail.len() < length as usize {
+        return Err(ChallengeError::Truncated(name));
+    }
+    let (field, tail) = tail.split_at(length as usize);
+    *rest = tail;
+    String::from_utf8(field.to_vec()).map_err(|_| ChallengeError::InvalidUtf8(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rejects_every_truncation() {
+        let challenge = Challenge {
+            version: CHALLENGE_VERSION,
+            key_id: [1; KEY_ID_LENGTH],
+            issued_at: 1_600_000_000,
+            nonce: [2; NONCE_LENGTH],
+            domain: "example.com".to_string(),
+            purpose: Purpose::AuthorizeOrder,
+            order_id: Some("order".to_string()),
+            tag: vec![3; HMAC_TAG_LENGTH],
+        };
+        let bytes = challenge.to_bytes().unwrap();
+        assert_eq!(Challenge::parse(&bytes), Ok(challenge));
+        for length in 0..bytes.len() {
+            assert!(Challenge::parse(&bytes[..length]).is_err());
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Signing and checking payment address challenges the way Sovrin wallets
+//! do, shared by the token website and its API tester so the two cannot
+//! drift apart.
+#[macro_use]
+extern crate arrayref;
+
+mod address;
+mod challenge;
+mod scheme;
+
+pub use address::{AddressFormat, PaymentAddress, DID_SOV, PAY_SOV, VERKEY_LENGTH};
+pub use challenge::{
+    Challenge, ChallengeError, Purpose, CHALLENGE_VERSION, ED25519_TAG_LENGTH, HMAC_TAG_LENGTH, KEY_ID_LENGTH,
+    NONCE_LENGTH, TIMESTAMP_LENGTH,
+};
+pub use scheme::{MessageFormat, Scheme, SignatureEncoding};
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature};
+use rand::RngCore;
+
+/// A new random ed25519 secret key
+pub fn generate_signing_key() -> Vec<u8> {
+    let mut rng = rand::rngs::OsRng{};
+    let mut key = vec![0u8; 32];
+    rng.fill_bytes(key.as_mut_slice());
+    key
+}
+
+/// The verkey of an ed25519 secret key. The key is either the 32 byte seed
+/// or the 64 byte seed and verkey that libsodium uses.
+pub fn verkey(secret_key: &[u8]) -> Result<[u8; VERKEY_LENGTH], String> {
+    Ok(keypair(secret_key)?.public.to_bytes())
+}
+
+/// The canonical `pay:sov:` address of a verkey
+pub fn encode_payment_address(verkey: &[u8]) -> Result<String, String> {
+    if verkey.len() != VERKEY_LENGTH {
+        return Err("Address is not an ed25519 verkey".to_string());
+    }
+    Ok(address::encode(array_ref!(verkey, 0, VERKEY_LENGTH)))
+}
+
+/// Accepts `pay:sov:`, `did:sov:` and raw base58 verkeys as well as
+/// abbreviated verkeys, see `AddressFormat`
+pub fn decode_payment_address(address: &str) -> Result<PaymentAddress, String> {
+    address::parse(address)
+}
+
+/// Sign a challenge as a wallet would, returning the encoded signature
+pub fn sign_challenge(secret_key: &[u8], challenge: &[u8], scheme: Scheme) -> Result<String, String> {
+    let signature = keypair(secret_key)?.sign(&scheme.message(challenge));
+    Ok(scheme.encode_signature(&signature.to_bytes()[..]))
+}
+
+/// Check a wallet's signature on a challenge. Malformed input is an error,
+/// a well formed signature that does not verify is `Ok(false)`.
+pub fn verify_challenge(verkey: &[u8], challenge: &[u8], signature: &str, scheme: Scheme) -> Result<bool, String> {
+    let public = PublicKey::from_bytes(verkey).map_err(|_| "Address cannot be converted to a public key".to_string())?;
+    let signature = scheme.decode_signature(signature)?;
+    let signature = Signature::from_bytes(&signature).map_err(|_| "Invalid signature".to_string())?;
+    Ok(public.verify(&scheme.message(challenge), &signature).is_ok())
+}
+
+fn keypair(secret_key: &[u8]) -> Result<Keypair, String> {
+    let seed = match secret_key.len() {
+        32 | 64 => &secret_key[..32],
+        _ => return Err("Signing keys must be 32 or 64 bytes".to_string()),
+    };
+    let secret = SecretKey::from_bytes(seed).map_err(|e| e.to_string())?;
+    let public = PublicKey::from(&secret);
+    if secret_key.len() == 64 && public.as_bytes()[..] != secret_key[32..] {
+        return Err("Signing key does not match its verkey".to_string());
+    }
+    Ok(Keypair { secret, public })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 8032 section 7.1 test 1
+    const SECRET_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const VERKEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const ADDRESS: &str = "pay:sov:2dqvheyJXzEYpywfm8g7TshzLbaXWTwHKQPkh4rYX3Db2B3TPZ";
+
+    #[test]
+    fn payment_address_vectors() {
+        let expected = hex::decode(VERKEY).unwrap();
+        assert_eq!(encode_payment_address(&expected).unwrap(), ADDRESS);
+        assert_eq!(decode_payment_address(ADDRESS).unwrap().verkey.to_vec(), expected);
+        assert_eq!(
+            decode_payment_address("FVen3X669xLzsi6N2V91DoiyzHzg1uAgqiT8jZ9nS96Z").unwrap().address,
+            ADDRESS
+        );
+        let mut libsodium = hex::decode(SECRET_KEY).unwrap();
+        libsodium.extend_from_slice(&expected);
+        assert_eq!(verkey(&libsodium).unwrap().to_vec(), expected);
+    }
+
+    #[test]
+    fn challenge_signature_vectors() {
+        let secret_key = hex::decode(SECRET_KEY).unwrap();
+        let public = hex::decode(VERKEY).unwrap();
+        let raw = Scheme { message: MessageFormat::Raw, encoding: SignatureEncoding::Hex };
+        let prefixed = Scheme::default();
+        let vectors = vec![
+            (raw, &b""[..], "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"),
+            (raw, &b"challenge"[..], "68bcf6998a9b49cfe8300e8c597be41848e87ae1dd2469923b5bd2f784575e11446bbae145f1de0ee6792754c2007ebbc83a23e7e49b065ba528be94476f7800"),
+            (prefixed, &b"challenge"[..], "KIrGozEDtaJdoeCgqySXpKIz4RojpIGoC0lOhPQAst4W9We3GTxXdNFxWLSBEVu0NZqYA443GO10Wk1CjNQSDA"),
+        ];
+        for (scheme, challenge, signature) in vectors {
+            assert_eq!(sign_challenge(&secret_key, challenge, scheme).unwrap(), signature);
+            assert!(verify_challenge(&public, challenge, signature, scheme).unwrap());
+            assert!(!verify_challenge(&public, b"other", signature, scheme).unwrap());
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+/// What the wallet actually signed
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum MessageFormat {
+    /// SHA-256 of `"\x6DSovrin Signed Message:\nLength: N\n"` and the challenge
+    #[serde(rename = "prefixed-sha256")]
+    PrefixedSha256,
+    /// The challenge bytes themselves, as libindy's `crypto_sign` does
+    #[serde(rename = "raw")]
+    Raw,
+}
+
+impl FromStr for MessageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefixed-sha256" => Ok(MessageFormat::PrefixedSha256),
+            "raw" => Ok(MessageFormat::Raw),
+            _ => Err(format!("Unknown message format: {}", s)),
+        }
+    }
+}
+
+impl Default for MessageFormat {
+    fn default() -> Self {
+        MessageFormat::PrefixedSha256
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum SignatureEncoding {
+    #[serde(rename = "base64url")]
+    Base64Url,
+    #[serde(rename = "base64")]
+    Base64,
+    #[serde(rename = "hex")]
+    Hex,
+}
+
+impl FromStr for SignatureEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "base64url" => Ok(SignatureEncoding::Base64Url),
+            "base64" => Ok(SignatureEncoding::Base64),
+            "hex" => Ok(SignatureEncoding::Hex),
+            _ => Err(format!("Unknown signature encoding: {}", s)),
+        }
+    }
+}
+
+impl Default for SignatureEncoding {
+    fn default() -> Self {
+        SignatureEncoding::Base64Url
+    }
+}
+
+/// How a wallet produced and encoded its signature. The default is what
+/// the website has always expected.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Scheme {
+    #[serde(default)]
+    pub message: MessageFormat,
+    #[serde(default)]
+    pub encoding: SignatureEncoding,
+}
+
+impl Scheme {
+    /// The bytes the signature is over
+    pub fn message(&self, challenge: &[u8]) -> Vec<u8> {
+        match self.message {
+            MessageFormat::PrefixedSha256 => {
+                let mut sha = Sha256::new();
+                sha.input(format!("\x6DSovrin Signed Message:\nLength: {}\n", challenge.len()).as_bytes());
+                sha.input(challenge);
+                sha.result().to_vec()
+            }
+            MessageFormat::Raw => challenge.to_vec(),
+        }
+    }
+
+    pub fn encode_signature(&self, signature: &[u8]) -> String {
+        match self.encoding {
+            SignatureEncoding::Base64Url => base64_url::encode(signature),
+            SignatureEncoding::Base64 => base64::encode(signature),
+            SignatureEncoding::Hex => hex::encode(signature),
+        }
+    }
+
+    pub fn decode_signature(&self, signature: &str) -> Result<Vec<u8>, String> {
+        let signature = signature.trim();
+        match self.encoding {
+            SignatureEncoding::Base64Url => base64_url::decode(signature).map_err(|e| e.to_string()),
+            SignatureEncoding::Base64 => base64::decode(signature).map_err(|e| e.to_string()),
+            SignatureEncoding::Hex => hex::decode(signature).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_encodings_agree() {
+        let signature = [0xfbu8, 0xff, 0x01];
+        for (encoding, encoded) in vec![
+            (SignatureEncoding::Base64Url, "-_8B"),
+            (SignatureEncoding::Base64, "+/8B"),
+            (SignatureEncoding::Hex, "fbff01"),
+        ] {
+            let scheme = Scheme { encoding, ..Scheme::default() };
+            assert_eq!(scheme.decode_signature(encoded).unwrap(), signature.to_vec());
+            assert_eq!(scheme.encode_signature(&signature), encoded);
+        }
+        let raw = Scheme { message: MessageFormat::Raw, ..Scheme::default() };
+        assert_eq!(raw.message(b"challenge"), b"challenge".to_vec());
+        assert_eq!(Scheme::default().message(b"challenge").len(), 32);
+    }
+}
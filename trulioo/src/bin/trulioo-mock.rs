@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+use structopt::StructOpt;
+use trulioo::{mock, TruliooRequest};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "trulioo-mock", about = "Serve or record Trulioo API fixtures")]
+enum Opt {
+    /// Answer Trulioo API calls from recorded fixtures
+    #[structopt(name = "serve")]
+    Serve {
+        #[structopt(short, long, default_value = "8001")]
+        port: u16,
+        /// Reject requests that do not send this API key
+        #[structopt(short, long)]
+        key: Option<String>,
+        #[structopt(short, long, parse(from_os_str))]
+        fixtures: Option<PathBuf>,
+    },
+    /// Capture fixtures from the real API with personal data scrubbed
+    #[structopt(name = "record")]
+    Record {
+        #[structopt(short, long)]
+        url: String,
+        #[structopt(short, long)]
+        key: String,
+        #[structopt(short, long, parse(from_os_str))]
+        fixtures: Option<PathBuf>,
+        /// Countries to record, all supported countries when absent
+        #[structopt(short, long)]
+        country: Vec<String>,
+    },
+}
+
+fn default_fixtures() -> PathBuf {
+    PathBuf::from(mock::FIXTURES)
+}
+
+fn main() {
+    match Opt::from_args() {
+        Opt::Serve { port, key, fixtures } => {
+            let fixtures = fixtures.unwrap_or_else(default_fixtures);
+            let server = match mock::MockServer::bind(("127.0.0.1", port), fixtures.clone(), key) {
+                Err(why) => panic!("Unable to listen on port {}: {}", port, why),
+                Ok(s) => s,
+            };
+            println!("Serving {:?} at {}", fixtures, server.url());
+            server.wait();
+        }
+        Opt::Record { url, key, fixtures, country } => {
+            let fixtures = fixtures.unwrap_or_else(default_fixtures);
+            let request = match TruliooRequest::builder().url(url).key(key).build() {
+                Err(why) => panic!("Unable to create the Trulioo client: {}", why),
+                Ok(r) => r,
+            };
+            match async_std::task::block_on(mock::record(&request, &fixtures, &country)) {
+                Err(why) => panic!("Unable to record fixtures: {}", why),
+                Ok(written) => {
+                    for path in written {
+                        println!("Wrote {:?}", path);
+                    }
+                }
+            }
+        }
+    }
+}
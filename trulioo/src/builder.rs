@@ -0,0 +1,87 @@
+use crate::{policy::CircuitBreaker, ClientPolicy, TruliooError, TruliooRequest};
+use isahc::{config::CaCertificate, HttpClient};
+use std::{path::PathBuf, sync::Arc};
+
+/// Configures the HTTP client a `TruliooRequest` owns
+#[derive(Clone, Debug, Default)]
+pub struct TruliooRequestBuilder {
+    key: String,
+    url: String,
+    policy: ClientPolicy,
+    proxy: Option<String>,
+    ca_bundle: Option<PathBuf>,
+    user_agent: Option<String>,
+    max_connections: Option<usize>,
+}
+
+impl TruliooRequestBuilder {
+    pub fn key<S: Into<String>>(mut self, key: S) -> Self {
+        self.key = key.into();
+        self
+    }
+
+    pub fn url<S: Into<String>>(mut self, url: S) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn policy(mut self, policy: ClientPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// e.g. `http://proxy.example.com:3128`, otherwise the usual proxy
+    /// environment variables apply
+    pub fn proxy<S: Into<String>>(mut self, proxy: S) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// A PEM file of CA certificates to trust instead of the system roots
+    pub fn ca_bundle<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.ca_bundle = Some(path.into());
+        self
+    }
+
+    pub fn user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Cap on open connections to Trulioo, unlimited by default
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn build(self) -> Result<TruliooRequest, TruliooError> {
+        let mut client = HttpClient::builder()
+            .timeout(self.policy.timeout)
+            .connect_timeout(self.policy.connect_timeout)
+            .default_header(
+                "User-Agent",
+                self.user_agent.unwrap_or_else(|| format!("trulioo-rs/{}", env!("CARGO_PKG_VERSION"))),
+            );
+        if let Some(proxy) = self.proxy {
+            let proxy = proxy.parse().map_err(|e| TruliooError::InvalidRequest(format!("Invalid proxy {}: {}", proxy, e)))?;
+            client = client.proxy(Some(proxy));
+        }
+        if let Some(path) = self.ca_bundle {
+            if !path.is_file() {
+                return Err(TruliooError::InvalidRequest(format!("CA bundle {:?} does not exist", path)));
+            }
+            client = client.ssl_ca_certificate(CaCertificate::file(path));
+        }
+        if let Some(max) = self.max_connections {
+            client = client.max_connections(max);
+        }
+        let client = client.build().map_err(|e| TruliooError::InvalidRequest(e.to_string()))?;
+        Ok(TruliooRequest {
+            key: self.key,
+            url: self.url,
+            breaker: Arc::new(CircuitBreaker::new(&self.policy)),
+            policy: self.policy,
+            client: Arc::new(client),
+        })
+    }
+}
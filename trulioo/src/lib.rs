@@ -1,13 +1,27 @@
+mod builder;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod policy;
+
+pub use builder::TruliooRequestBuilder;
+pub use policy::{CircuitState, CircuitStatus, ClientPolicy};
+
 use indexmap::IndexMap;
 use isahc::prelude::*;
+use policy::CircuitBreaker;
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc, time::Duration};
 use zeroize::Zeroize;
 
-pub const TRIAL_BASE_URL: &str = "https://gateway.Trulioo.com/trial/configuration";
+/// Trial keys only work against this gateway
+pub const TRIAL_BASE_URL: &str = "https://gateway.trulioo.com/trial";
 pub const BASE_URL: &str = "https://api.globaldatacompany.com/";
 pub const API_KEY_HEADER: &str = "x-trulioo-api-key";
 pub const CONFIGURATION_NAME: &str = "Identity%20Verification";
+pub const IDENTITY_VERIFICATION: &str = "Identity Verification";
+pub const BUSINESS_VERIFICATION: &str = "Business Verification";
+pub const BUSINESS_SEARCH: &str = "Business Search";
+pub const DOCUMENT_VERIFICATION: &str = "Document Verification";
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum Gender {
@@ -33,41 +47,53 @@ pub enum DocumentTypes {
     ResidencePermit
 }
 
-#[derive(Clone, Debug, Zeroize)]
-#[zeroize(drop)]
+/// Clones share the HTTP client and its connection pool as well as the
+/// circuit breaker
+#[derive(Clone)]
 pub struct TruliooRequest {
     pub key: String,
     pub url: String,
+    pub policy: ClientPolicy,
+    client: Arc<HttpClient>,
+    breaker: Arc<CircuitBreaker>,
 }
 
 impl TruliooRequest {
-    pub async fn get_country_codes(&self) -> Result<Vec<String>, String> {
+    pub fn builder() -> TruliooRequestBuilder {
+        TruliooRequestBuilder::default()
+    }
+
+    /// Whether calls are currently failing fast
+    pub fn circuit_status(&self) -> CircuitStatus {
+        self.breaker.status()
+    }
+
+    pub async fn get_country_codes(&self) -> Result<Vec<String>, TruliooError> {
         let body = self
             .get(format!(
                 "{}/configuration/v1/countrycodes/{}",
                 self.url, CONFIGURATION_NAME
             ))
             .await?;
-        let result: Vec<String> = serde_json::from_str(&body).map_err(|e| format!("{:?}", e))?;
+        let result: Vec<String> = decode(&body)?;
         Ok(result)
     }
 
     pub async fn get_country_subdivisions<S: Display>(
         &self,
         country: S,
-    ) -> Result<Vec<Subdivision>, String> {
+    ) -> Result<Vec<Subdivision>, TruliooError> {
         let body = self
             .get(format!(
                 "{}/configuration/v1/countrysubdivisions/{}",
                 self.url, country
             ))
             .await?;
-        let result: Vec<Subdivision> =
-            serde_json::from_str(&body).map_err(|e| format!("{:?}", e))?;
+        let result: Vec<Subdivision> = decode(&body)?;
         Ok(result)
     }
 
-    pub async fn get_fields<S: Display>(&self, country: S) -> Result<String, String> {
+    pub async fn get_fields<S: Display>(&self, country: S) -> Result<String, TruliooError> {
         let body = self
             .get(format!(
                 "{}/configuration/v1/fields/{}/{}",
@@ -77,7 +103,7 @@ impl TruliooRequest {
         Ok(body)
     }
 
-    pub async fn get_recommended_fields<S: Display>(&self, country: S) -> Result<String, String> {
+    pub async fn get_recommended_fields<S: Display>(&self, country: S) -> Result<String, TruliooError> {
         let body = self
             .get(format!(
                 "{}/configuration/v1/recommendedfields/{}/{}",
@@ -87,14 +113,14 @@ impl TruliooRequest {
         Ok(body)
     }
 
-    pub async fn get_consents<S: Display>(&self, country: S) -> Result<Vec<Consent>, String> {
+    pub async fn get_consents<S: Display>(&self, country: S) -> Result<Vec<Consent>, TruliooError> {
         let body = self
             .get(format!(
                 "{}/configuration/v1/consents/{}/{}",
                 self.url, CONFIGURATION_NAME, country
             ))
             .await?;
-        let result: Vec<String> = serde_json::from_str(&body).map_err(|e| format!("{:?}", e))?;
+        let result: Vec<String> = decode(&body)?;
         let result = result
             .iter()
             .map(|c| Consent {
@@ -109,102 +135,261 @@ impl TruliooRequest {
     pub async fn get_detailed_consents<S: Display>(
         &self,
         country: S,
-    ) -> Result<Vec<Consent>, String> {
+    ) -> Result<Vec<Consent>, TruliooError> {
         let body = self
             .get(format!(
                 "{}/configuration/v1/detailedConsents/{}/{}",
                 self.url, CONFIGURATION_NAME, country
             ))
             .await?;
-        let result: Vec<Consent> = serde_json::from_str(&body).map_err(|e| format!("{:?}", e))?;
+        let result: Vec<Consent> = decode(&body)?;
         Ok(result)
     }
 
     pub async fn get_test_entities<S: Display>(
         &self,
         country: S,
-    ) -> Result<Vec<Option<Entity>>, String> {
+    ) -> Result<Vec<Option<Entity>>, TruliooError> {
         let body = self
             .get(format!(
                 "{}/configuration/v1/testentities/{}/{}",
                 self.url, CONFIGURATION_NAME, country
             ))
             .await?;
-        let result: Vec<Option<Entity>> =
-            serde_json::from_str(&body).map_err(|e| format!("{:?}", e))?;
+        let result: Vec<Option<Entity>> = decode(&body)?;
         Ok(result)
     }
 
-    pub async fn verify_identity<S: Display>(
+    pub async fn verify_identity(
         &self,
         request: &VerifyIdentityRequest,
-    ) -> Result<VerifyIdentityResponse, String> {
-        let post_body = serde_json::to_string(request).map_err(|e| format!("{:?}", e))?;
+    ) -> Result<VerifyIdentityResponse, TruliooError> {
+        let post_body = serde_json::to_string(request).map_err(|e| TruliooError::InvalidRequest(e.to_string()))?;
         let body = self
             .post(format!("{}/verifications/v1/verify", self.url), post_body)
             .await?;
-        let result: VerifyIdentityResponse =
-            serde_json::from_str(&body).map_err(|e| format!("{:?}", e))?;
+        let result: VerifyIdentityResponse = decode(&body)?;
+        Ok(result)
+    }
+
+    pub async fn verify_business(
+        &self,
+        request: &VerifyIdentityRequest,
+    ) -> Result<VerifyIdentityResponse, TruliooError> {
+        if request.datafields.business.is_none() {
+            return Err(TruliooError::InvalidRequest("Business fields are required for business verification".to_string()));
+        }
+        self.verify_identity(request).await
+    }
+
+    pub async fn verify_document(
+        &self,
+        request: &VerifyIdentityRequest,
+    ) -> Result<VerifyIdentityResponse, TruliooError> {
+        if request.datafields.document.is_none() {
+            return Err(TruliooError::InvalidRequest("Document fields are required for document verification".to_string()));
+        }
+        self.verify_identity(request).await
+    }
+
+    pub async fn search_business(
+        &self,
+        request: &BusinessSearchRequest,
+    ) -> Result<BusinessSearchResponse, TruliooError> {
+        let post_body = serde_json::to_string(request).map_err(|e| TruliooError::InvalidRequest(e.to_string()))?;
+        let body = self
+            .post(format!("{}/business/v1/search", self.url), post_body)
+            .await?;
+        let result: BusinessSearchResponse = decode(&body)?;
         Ok(result)
     }
 
     pub async fn get_document_types<S: Display>(
         &self,
         country: S,
-    ) -> Result<IndexMap<String, Vec<DocumentTypes>>, String> {
+    ) -> Result<IndexMap<String, Vec<DocumentTypes>>, TruliooError> {
         let body = self
             .get(format!(
                 "{}/configuration/v1/documentTypes/{}",
                 self.url, country
             ))
             .await?;
-        let result: IndexMap<String, Vec<DocumentTypes>> =
-            serde_json::from_str(&body).map_err(|e| format!("{:?}", e))?;
+        let result: IndexMap<String, Vec<DocumentTypes>> = decode(&body)?;
         Ok(result)
     }
 
-    async fn post(&self, url: String, request: String) -> Result<String, String> {
-        let mut response = Request::post(&url)
+    async fn post(&self, url: String, request: String) -> Result<String, TruliooError> {
+        let request = Request::post(&url)
             .header("Accept", "application/json")
             .header("Content-Type", "application/json")
             .header(API_KEY_HEADER, &self.key)
-            .body(request)
-            .map_err(|e| format!("{:?}", e))?
-            .send_async()
-            .await
-            .map_err(|e| format!("{:?}", e))?;
-
-        let body = response
-            .text_async()
-            .await
-            .map_err(|e| format!("{:?}", e))?;
-        Ok(body)
+            .body(Body::from(request))
+            .map_err(|e| TruliooError::InvalidRequest(e.to_string()))?;
+        self.send(request).await
     }
 
-    async fn get(&self, url: String) -> Result<String, String> {
-        let mut response = Request::get(&url)
-            .header("Accept", "application/json")
-            .header(API_KEY_HEADER, &self.key)
-            .body(Body::empty())
-            .map_err(|e| format!("{:?}", e))?
-            .send_async()
-            .await
-            .map_err(|e| format!("{:?}", e))?;
-
-        let body = response
-            .text_async()
-            .await
-            .map_err(|e| format!("{:?}", e))?;
-        Ok(body)
+    /// GETs are idempotent so they are retried with backoff
+    async fn get(&self, url: String) -> Result<String, TruliooError> {
+        let mut attempt = 0;
+        loop {
+            let request = Request::get(&url)
+                .header("Accept", "application/json")
+                .header(API_KEY_HEADER, &self.key)
+                .body(Body::empty())
+                .map_err(|e| TruliooError::InvalidRequest(e.to_string()))?;
+            let result = self.send(request).await;
+            let delay = match result {
+                Err(ref e) if attempt < self.policy.max_retries && e.is_retryable() => match *e {
+                    TruliooError::RateLimited { retry_after: Some(s) } => Duration::from_secs(s),
+                    _ => self.policy.backoff(attempt),
+                },
+                _ => return result,
+            };
+            // Retrying before the server asked would only be rate limited
+            // again, so a Retry-After beyond backoff_max is left to the caller
+            if delay > self.policy.backoff_max {
+                return result;
+            }
+            async_std::task::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send(&self, request: Request<Body>) -> Result<String, TruliooError> {
+        self.breaker.allow()?;
+        let result = match self.client.send_async(request).await {
+            Ok(response) => read_response(response).await,
+            Err(e) => Err(TruliooError::Transport(e.to_string())),
+        };
+        self.breaker.record(&result);
+        result
+    }
+}
+
+impl std::fmt::Debug for TruliooRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TruliooRequest")
+            .field("url", &self.url)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl Drop for TruliooRequest {
+    fn drop(&mut self) {
+        self.key.zeroize();
+        self.url.zeroize();
+    }
+}
+
+async fn read_response(mut response: Response<Body>) -> Result<String, TruliooError> {
+    let status = response.status().as_u16();
+    let retry_after = response
+        .headers()
+        .get("Retry-After")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let body = response
+        .text_async()
+        .await
+        .map_err(|e| TruliooError::Transport(e.to_string()))?;
+    check_status(status, retry_after, body)
+}
+
+/// Turn anything but a 2xx into the matching error
+fn check_status(status: u16, retry_after: Option<u64>, body: String) -> Result<String, TruliooError> {
+    match status {
+        200..=299 => Ok(body),
+        401 | 403 => Err(TruliooError::Unauthorized),
+        429 => Err(TruliooError::RateLimited { retry_after }),
+        400..=499 => match serde_json::from_str::<ErrorBody>(&body) {
+            Ok(e) => Err(TruliooError::Api {
+                code: e.code.map(|c| match c {
+                    serde_json::Value::String(s) => s,
+                    c => c.to_string(),
+                }),
+                message: e.message,
+            }),
+            Err(_) => Err(TruliooError::Api { code: None, message: body }),
+        },
+        _ => Err(TruliooError::Server { status, body }),
+    }
+}
+
+fn decode<T: serde::de::DeserializeOwned>(body: &str) -> Result<T, TruliooError> {
+    serde_json::from_str(body).map_err(|e| TruliooError::Decode {
+        body: body.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// What Trulioo sends back with a 4xx
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(rename = "Code", alias = "code", default)]
+    code: Option<serde_json::Value>,
+    #[serde(rename = "Message", alias = "message")]
+    message: String,
+}
+
+/// Why a call to Trulioo failed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TruliooError {
+    /// The request or the client could not be built, nothing was sent
+    InvalidRequest(String),
+    /// No response, e.g. DNS, TLS or connection failures
+    Transport(String),
+    /// The API key was rejected
+    Unauthorized,
+    /// Too many requests, try again after `retry_after` seconds if given
+    RateLimited { retry_after: Option<u64> },
+    /// A 5xx or other unexpected status
+    Server { status: u16, body: String },
+    /// A success status with a body that is not what was expected
+    Decode { body: String, reason: String },
+    /// Trulioo rejected the request
+    Api { code: Option<String>, message: String },
+    /// Trulioo has been failing, the call was not attempted
+    CircuitOpen { retry_after: u64 },
+}
+
+impl TruliooError {
+    /// Worth trying the same request again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TruliooError::Transport(_) | TruliooError::Server { .. } | TruliooError::RateLimited { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Counts towards opening the circuit breaker
+    pub(crate) fn is_outage(&self) -> bool {
+        match self {
+            TruliooError::Transport(_) | TruliooError::Server { .. } => true,
+            _ => false,
+        }
     }
 }
 
-impl Default for TruliooRequest {
-    fn default() -> Self {
-        TruliooRequest { key: String::new(), url: String::new() }
+impl Display for TruliooError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TruliooError::InvalidRequest(e) => write!(f, "Invalid Trulioo request: {}", e),
+            TruliooError::Transport(e) => write!(f, "Unable to reach Trulioo: {}", e),
+            TruliooError::Unauthorized => write!(f, "Trulioo rejected the API key"),
+            TruliooError::RateLimited { retry_after: Some(s) } => write!(f, "Trulioo rate limit reached, retry after {} seconds", s),
+            TruliooError::RateLimited { retry_after: None } => write!(f, "Trulioo rate limit reached"),
+            TruliooError::Server { status, body } => write!(f, "Trulioo returned {}: {}", status, body),
+            TruliooError::Decode { reason, .. } => write!(f, "Unexpected response from Trulioo: {}", reason),
+            TruliooError::Api { code: Some(c), message } => write!(f, "Trulioo error {}: {}", c, message),
+            TruliooError::Api { code: None, message } => write!(f, "Trulioo error: {}", message),
+            TruliooError::CircuitOpen { retry_after } => write!(f, "Trulioo is unavailable, retry after {} seconds", retry_after),
+        }
     }
 }
 
+impl std::error::Error for TruliooError {}
+
 macro_rules! api_obj_impl {
     ($class:ident, $($rename:expr => $field:ident: $ty:ty),+) => {
         #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -323,7 +508,7 @@ api_obj_impl!(DriverLicense,
 api_obj_impl!(VerifyIdentityRequest,
               "AcceptTruliooTermsAndConditions" => accept_trulioo_terms_and_conditions: bool,
               "ConfigurationName" => configuration_name: String,
-              "CallBackUrl" => callback_url: String,
+              "CallBackUrl" => callback_url: Option<String>,
               "ConsentForDataSources" => consent_for_data_sources: Vec<String>,
               "CountryCode" => country_code: String,
               "CustomerReferenceID" => customer_reference_id: String,
@@ -338,8 +523,59 @@ api_obj_impl!(DataFields,
               "DriverLicence" => driver_license: Option<DriverLicense>,
               "NationalIds" => national_ids: Option<Vec<NationalIds>>,
               "Passport" => passport: Option<Passport>,
+              "Business" => business: Option<Business>,
+              "Document" => document: Option<Document>,
               "CountrySpecific"=> country_specific: Option<IndexMap<String, IndexMap<String, String>>>);
 
+api_obj_impl!(Document,
+              "DocumentFrontImage" => front_image: String,
+              "DocumentBackImage" => back_image: Option<String>,
+              "LivePhoto" => live_photo: Option<String>,
+              "DocumentType" => document_type: DocumentTypes);
+
+api_obj_impl!(Business,
+              "BusinessName" => name: Option<String>,
+              "BusinessRegistrationNumber" => registration_number: Option<String>,
+              "DunsNumber" => duns_number: Option<String>,
+              "DayOfIncorporation" => day_of_incorporation: Option<usize>,
+              "MonthOfIncorporation" => month_of_incorporation: Option<usize>,
+              "YearOfIncorporation" => year_of_incorporation: Option<usize>,
+              "JurisdictionOfIncorporation" => jurisdiction_of_incorporation: Option<String>);
+
+api_obj_impl!(BusinessSearchRequest,
+              "AcceptTruliooTermsAndConditions" => accept_trulioo_terms_and_conditions: bool,
+              "ConfigurationName" => configuration_name: String,
+              "CallBackUrl" => callback_url: Option<String>,
+              "ConsentForDataSources" => consent_for_data_sources: Vec<String>,
+              "CountryCode" => country_code: String,
+              "Business" => business: Business,
+              "Timeout" => timeout: Option<usize>);
+
+api_obj_impl!(BusinessSearchResponse,
+              "TransactionID" => transaction_id: String,
+              "UploadedDt" => uploaded_date: String,
+              "CountryCode" => country_code: String,
+              "ProductName" => product_name: String,
+              "Record" => record: BusinessSearchRecord,
+              "Errors" => errors: Vec<ServiceError>);
+
+api_obj_impl!(BusinessSearchRecord,
+              "TransactionRecordID" => id: String,
+              "RecordStatus" => status: String,
+              "DatasourceResults" => data_source_results: Vec<BusinessSearchDatasourceResult>,
+              "Errors" => errors: Vec<ServiceError>);
+
+api_obj_impl!(BusinessSearchDatasourceResult,
+              "DatasourceName" => name: String,
+              "Results" => results: Vec<BusinessSearchResult>,
+              "Errors" => errors: Vec<ServiceError>);
+
+api_obj_impl!(BusinessSearchResult,
+              "BusinessName" => name: Option<String>,
+              "BusinessRegistrationNumber" => registration_number: Option<String>,
+              "JurisdictionOfIncorporation" => jurisdiction_of_incorporation: Option<String>,
+              "DunsNumber" => duns_number: Option<String>);
+
 api_obj_impl!(AdditionalFieldsPersonInfo,
               "FullName" => full_name: String);
 
@@ -352,15 +588,31 @@ api_obj_impl!(VerifyIdentityResponse,
               "CountryCode" => country_code: String,
               "ProductName" => product_name: String,
               "Record" => record: VerifyRecord,
-              "Errors" => errors: Vec<String>);
+              "Errors" => errors: Vec<ServiceError>);
 
 api_obj_impl!(VerifyRecord,
               "TransactionRecordID" => id: String,
               "RecordStatus" => status: String,
-              "DatasourceResults" => data_source_results: Vec<String>,
-              "Errors" => errors: Vec<String>,
-              "Rule" => rule: String,
-              "Note" => note: String);
+              "DatasourceResults" => data_source_results: Vec<DatasourceResult>,
+              "Errors" => errors: Vec<ServiceError>,
+              "Rule" => rule: Option<Rule>);
+
+api_obj_impl!(DatasourceResult,
+              "DatasourceName" => name: String,
+              "DatasourceFields" => fields: Vec<DatasourceField>,
+              "Errors" => errors: Vec<ServiceError>);
+
+api_obj_impl!(DatasourceField,
+              "FieldName" => name: String,
+              "Status" => status: String);
+
+api_obj_impl!(Rule,
+              "RuleName" => name: Option<String>,
+              "Note" => note: Option<String>);
+
+api_obj_impl!(ServiceError,
+              "Code" => code: String,
+              "Message" => message: String);
 
 #[cfg(test)]
 mod tests {
@@ -368,21 +620,110 @@ mod tests {
     use async_std::task;
     use std::env;
     use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
     use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
     use toml;
 
+    /// Answers one connection per status and Retry-After, counting the
+    /// requests
+    fn scripted_server(statuses: Vec<(u16, u64)>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let requests = count.clone();
+        thread::spawn(move || {
+            for ((status, retry_after), stream) in statuses.into_iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+                let mut buffer = [0u8; 4096];
+                let _ = stream.read(&mut buffer);
+                requests.fetch_add(1, Ordering::SeqCst);
+                let body = r#"["US"]"#;
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} Scripted\r\nRetry-After: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    retry_after,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (url, count)
+    }
+
+    #[test]
+    fn only_retryable_errors_are_retried() {
+        let policy = ClientPolicy {
+            max_retries: 2,
+            backoff_base: Duration::from_millis(1),
+            backoff_max: Duration::from_millis(20),
+            failure_threshold: 10,
+            ..ClientPolicy::default()
+        };
+        let request = |url: String| TruliooRequest::builder().key("key").url(url).policy(policy.clone()).build().unwrap();
+
+        let (url, count) = scripted_server(vec![(503, 0), (429, 0), (200, 0)]);
+        assert_eq!(task::block_on(request(url).get_country_codes()), Ok(vec!["US".to_string()]));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // Asked to wait longer than backoff_max, the caller hears so at once
+        let (url, count) = scripted_server(vec![(429, 60), (200, 0)]);
+        assert_eq!(task::block_on(request(url).get_country_codes()), Err(TruliooError::RateLimited { retry_after: Some(60) }));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        let (url, count) = scripted_server(vec![(500, 0), (500, 0), (500, 0), (200, 0)]);
+        assert!(match task::block_on(request(url).get_country_codes()) {
+            Err(TruliooError::Server { status: 500, .. }) => true,
+            _ => false,
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        let (url, count) = scripted_server(vec![(400, 0), (200, 0)]);
+        assert!(task::block_on(request(url).get_country_codes()).is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn status_codes_map_to_errors() {
+        assert_eq!(check_status(200, None, "[]".to_string()), Ok("[]".to_string()));
+        assert_eq!(check_status(401, None, String::new()), Err(TruliooError::Unauthorized));
+        assert_eq!(check_status(429, Some(5), String::new()), Err(TruliooError::RateLimited { retry_after: Some(5) }));
+        assert_eq!(
+            check_status(500, None, "oops".to_string()),
+            Err(TruliooError::Server { status: 500, body: "oops".to_string() })
+        );
+        assert_eq!(
+            check_status(400, None, r#"{"Code": 1001, "Message": "Missing field"}"#.to_string()),
+            Err(TruliooError::Api { code: Some("1001".to_string()), message: "Missing field".to_string() })
+        );
+        assert!(match decode::<Vec<String>>("<html>") {
+            Err(TruliooError::Decode { body, .. }) => body == "<html>",
+            _ => false,
+        });
+    }
+
     #[test]
     fn get_country_codes_works() {
 
         let request;
+        // Keeps the mock running when there are no live credentials
+        let mut _mock = None;
         if Path::new(".env").exists() {
             let config: Config = toml::from_str(&fs::read_to_string(".env").unwrap()).unwrap();
             request = config.api.into();
+        } else if let Ok(key) = env::var("TRULIOO_API_KEY") {
+            request = TruliooRequest::builder()
+                .key(key)
+                .url(env::var("TRULIOO_API_URL").unwrap())
+                .build()
+                .unwrap()
         } else {
-            request = TruliooRequest {
-                key: env::var("TRULIOO_API_KEY").unwrap(),
-                url: env::var("TRULIOO_API_URL").unwrap()
-            }
+            let mock = mock::MockServer::start(mock::FIXTURES).unwrap();
+            request = mock.request();
+            _mock = Some(mock);
         }
 
         task::block_on(async {
@@ -404,6 +745,65 @@ mod tests {
         });
     }
 
+    #[test]
+    fn mock_serves_recorded_fixtures() {
+        let mock = mock::MockServer::start(mock::FIXTURES).unwrap();
+        let request = mock.request();
+        task::block_on(async {
+            assert!(!request.get_detailed_consents("US").await.unwrap().is_empty());
+            let entity = request.get_test_entities("US").await.unwrap().into_iter().flatten().next().unwrap();
+            let verify = VerifyIdentityRequest {
+                accept_trulioo_terms_and_conditions: true,
+                configuration_name: IDENTITY_VERIFICATION.to_string(),
+                callback_url: None,
+                consent_for_data_sources: Vec::new(),
+                country_code: "US".to_string(),
+                customer_reference_id: "test".to_string(),
+                datafields: DataFields {
+                    person_info: entity.person_info,
+                    location: entity.location,
+                    communication: None,
+                    driver_license: None,
+                    national_ids: None,
+                    passport: None,
+                    business: None,
+                    document: None,
+                    country_specific: None,
+                },
+                timeout: None,
+                cleansed_address: None,
+            };
+            assert_eq!(request.verify_identity(&verify).await.unwrap().record.status, "match");
+            let search = BusinessSearchRequest {
+                accept_trulioo_terms_and_conditions: true,
+                configuration_name: BUSINESS_SEARCH.to_string(),
+                callback_url: None,
+                consent_for_data_sources: Vec::new(),
+                country_code: "US".to_string(),
+                business: Business {
+                    name: Some("For Sale".to_string()),
+                    registration_number: None,
+                    duns_number: None,
+                    day_of_incorporation: None,
+                    month_of_incorporation: None,
+                    year_of_incorporation: None,
+                    jurisdiction_of_incorporation: Some("US".to_string()),
+                },
+                timeout: None,
+            };
+            let found = request.search_business(&search).await.unwrap();
+            assert_eq!(found.record.data_source_results[0].results[0].registration_number, Some("01-23456789".to_string()));
+            assert!(match request.get_test_entities("ZZ").await {
+                Err(TruliooError::Api { .. }) => true,
+                _ => false,
+            });
+        });
+
+        let locked = mock::MockServer::bind("127.0.0.1:0", mock::FIXTURES, Some("secret".to_string())).unwrap();
+        let result = task::block_on(locked.request().get_country_codes());
+        assert_eq!(result, Err(TruliooError::Unauthorized));
+    }
+
     #[derive(Deserialize)]
     struct Config {
         api: Api,
@@ -417,10 +817,7 @@ mod tests {
 
     impl From<Api> for TruliooRequest {
         fn from(a: Api) -> Self {
-            TruliooRequest {
-                key: a.key,
-                url: a.url,
-            }
+            TruliooRequest::builder().key(a.key).url(a.url).build().unwrap()
         }
     }
 }
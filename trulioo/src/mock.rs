@@ -0,0 +1,390 @@
+//! A stand-in for the Trulioo API that serves recorded responses, so the
+//! client and the website can be exercised without credentials.
+//!
+//! Fixtures are the JSON bodies Trulioo returned, laid out as
+//!
+//! ```text
+//! countrycodes.json
+//! consents/<country>.json
+//! detailedConsents/<country>.json
+//! fields/<country>.json
+//! testentities/<country>.json
+//! documentTypes/<country>.json
+//! verify.json
+//! businessSearch.json
+//! ```
+//!
+//! `record` captures them from the real API with personal data replaced by
+//! placeholders.
+use crate::{
+    DataFields, Entity, TruliooRequest, VerifyIdentityRequest, API_KEY_HEADER, CONFIGURATION_NAME,
+    IDENTITY_VERIFICATION,
+};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// The fixtures recorded with this crate
+pub const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+
+/// Endpoints that are recorded per country
+pub const COUNTRY_ENDPOINTS: &[&str] = &["consents", "detailedConsents", "fields", "testentities", "documentTypes"];
+
+/// The fixture file answering a request, relative to the fixture directory
+pub fn fixture_name(method: &str, path: &str) -> Option<String> {
+    let path = path.split('?').next().unwrap_or("");
+    let segments = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<&str>>();
+    match (method, segments.as_slice()) {
+        ("GET", ["configuration", "v1", "countrycodes", _]) => Some("countrycodes.json".to_string()),
+        ("GET", ["configuration", "v1", "documentTypes", c]) => country_code(c).map(|c| format!("documentTypes/{}.json", c)),
+        ("GET", ["configuration", "v1", endpoint, _, c]) if COUNTRY_ENDPOINTS.contains(endpoint) => {
+            country_code(c).map(|c| format!("{}/{}.json", endpoint, c))
+        }
+        ("POST", ["verifications", "v1", "verify"]) => Some("verify.json".to_string()),
+        ("POST", ["business", "v1", "search"]) => Some("businessSearch.json".to_string()),
+        _ => None,
+    }
+}
+
+/// Country codes end up in file names so nothing but letters and digits
+fn country_code(code: &str) -> Option<&str> {
+    Some(code).filter(|c| !c.is_empty() && c.chars().all(|ch| ch.is_ascii_alphanumeric()))
+}
+
+/// Serves fixtures over HTTP on a background thread until dropped
+pub struct MockServer {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    /// Listen on a free local port, accepting any API key
+    pub fn start<P: Into<PathBuf>>(fixtures: P) -> io::Result<Self> {
+        Self::bind("127.0.0.1:0", fixtures, None)
+    }
+
+    /// When `key` is set requests with a different API key get a 401
+    pub fn bind<A: ToSocketAddrs, P: Into<PathBuf>>(addr: A, fixtures: P, key: Option<String>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stop = shutdown.clone();
+        let fixtures = Arc::new(fixtures.into());
+        let key = Arc::new(key);
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let (fixtures, key) = (fixtures.clone(), key.clone());
+                    thread::spawn(move || {
+                        let _ = serve_connection(stream, &fixtures, (*key).as_deref());
+                    });
+                }
+            }
+        });
+        Ok(Self { addr, shutdown, handle: Some(handle) })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// What to use as the `TruliooRequest` url
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// A client pointed at this server
+    pub fn request(&self) -> TruliooRequest {
+        TruliooRequest::builder()
+            .key("mock")
+            .url(self.url())
+            .build()
+            .expect("Unable to create an HTTP client")
+    }
+
+    /// Serve until the process exits
+    pub fn wait(mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.shutdown.store(true, Ordering::SeqCst);
+            // Wake up the accept loop so it sees the flag
+            let _ = TcpStream::connect(self.addr);
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve_connection(mut stream: TcpStream, fixtures: &Path, key: Option<&str>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("").to_string();
+
+    let mut content_length = 0;
+    let mut api_key = None;
+    let mut expect_continue = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let mut split = header.splitn(2, ':');
+        let name = split.next().unwrap_or("").trim().to_ascii_lowercase();
+        let value = split.next().unwrap_or("").trim().to_string();
+        match name.as_str() {
+            "content-length" => content_length = value.parse().unwrap_or(0),
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            n if n == API_KEY_HEADER => api_key = Some(value),
+            _ => {}
+        }
+    }
+    if expect_continue {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    let (status, response) = respond(fixtures, key, &method, &target, api_key.as_deref(), &body);
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        response.len(),
+        response
+    )?;
+    stream.flush()
+}
+
+fn respond(fixtures: &Path, key: Option<&str>, method: &str, target: &str, api_key: Option<&str>, body: &[u8]) -> (u16, String) {
+    let error = |message: &str| json!({ "Message": message }).to_string();
+    if key.is_some() && key != api_key {
+        return (401, error("Authorization has been denied for this request."));
+    }
+    let name = match fixture_name(method, target) {
+        Some(n) => n,
+        None => return (404, error("No HTTP resource was found that matches the request URI.")),
+    };
+    let fixture = match fs::read_to_string(fixtures.join(&name)) {
+        Ok(f) => f,
+        Err(_) => return (404, error(&format!("No fixture recorded for {}", name))),
+    };
+    if method != "POST" {
+        return (200, fixture);
+    }
+    // Answer for the country that was asked about
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(r) => r,
+        Err(_) => return (400, error("The request is invalid.")),
+    };
+    let mut response: Value = match serde_json::from_str(&fixture) {
+        Ok(r) => r,
+        Err(e) => return (500, error(&e.to_string())),
+    };
+    if let (Some(country), Some(r)) = (request.get("CountryCode"), response.as_object_mut()) {
+        r.insert("CountryCode".to_string(), country.clone());
+    }
+    (200, response.to_string())
+}
+
+/// Capture fixtures from the real API. Every country Trulioo supports is
+/// recorded when `countries` is empty, `verify.json` comes from verifying
+/// the first test entity of the first country.
+pub async fn record(request: &TruliooRequest, fixtures: &Path, countries: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut written = Vec::new();
+    let codes = request
+        .get(format!("{}/configuration/v1/countrycodes/{}", request.url, CONFIGURATION_NAME))
+        .await
+        .map_err(|e| e.to_string())?;
+    written.push(save(fixtures, "countrycodes.json", &codes)?);
+    let countries = if countries.is_empty() {
+        serde_json::from_str::<Vec<String>>(&codes).map_err(|e| e.to_string())?
+    } else {
+        countries.to_vec()
+    };
+
+    for country in &countries {
+        for endpoint in COUNTRY_ENDPOINTS {
+            let url = match *endpoint {
+                "documentTypes" => format!("{}/configuration/v1/documentTypes/{}", request.url, country),
+                e => format!("{}/configuration/v1/{}/{}/{}", request.url, e, CONFIGURATION_NAME, country),
+            };
+            let body = request.get(url).await.map_err(|e| e.to_string())?;
+            written.push(save(fixtures, &format!("{}/{}.json", endpoint, country), &body)?);
+        }
+    }
+
+    if let Some(country) = countries.first() {
+        let entities = request.get_test_entities(country).await.map_err(|e| e.to_string())?;
+        if let Some(entity) = entities.into_iter().flatten().next() {
+            let consents = request.get_consents(country).await.map_err(|e| e.to_string())?;
+            let verify = verify_request(country, entity, consents.into_iter().map(|c| c.name).collect());
+            let body = serde_json::to_string(&verify).map_err(|e| e.to_string())?;
+            let response = request
+                .post(format!("{}/verifications/v1/verify", request.url), body)
+                .await
+                .map_err(|e| e.to_string())?;
+            written.push(save(fixtures, "verify.json", &response)?);
+        }
+    }
+    Ok(written)
+}
+
+fn verify_request(country: &str, entity: Entity, consents: Vec<String>) -> VerifyIdentityRequest {
+    VerifyIdentityRequest {
+        accept_trulioo_terms_and_conditions: true,
+        configuration_name: IDENTITY_VERIFICATION.to_string(),
+        callback_url: None,
+        consent_for_data_sources: consents,
+        country_code: country.to_string(),
+        customer_reference_id: "fixture".to_string(),
+        datafields: DataFields {
+            person_info: entity.person_info,
+            location: entity.location,
+            communication: entity.communication,
+            driver_license: entity.driver_license,
+            national_ids: None,
+            passport: entity.passport,
+            business: None,
+            document: None,
+            country_specific: entity.country_specific,
+        },
+        timeout: None,
+        cleansed_address: None,
+    }
+}
+
+fn save(fixtures: &Path, name: &str, body: &str) -> Result<PathBuf, String> {
+    let path = fixtures.join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Unable to create {:?}: {}", parent, e))?;
+    }
+    let contents = match serde_json::from_str::<Value>(body) {
+        Ok(mut value) => {
+            scrub(&mut value);
+            serde_json::to_string_pretty(&value).map_err(|e| e.to_string())?
+        }
+        Err(_) => body.to_string(),
+    };
+    fs::write(&path, contents).map_err(|e| format!("Unable to write {:?}: {}", path, e))?;
+    Ok(path)
+}
+
+/// Replace personal data with placeholders of the same type so fixtures
+/// still deserialize
+pub fn scrub(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                match name.as_str() {
+                    // Values keyed by country specific field names
+                    "CountrySpecific" | "AppendedFields" => scrub_all(field),
+                    n => match placeholder(n) {
+                        Some(p) if !field.is_null() && !field.is_object() && !field.is_array() => {
+                            *field = if field.is_number() { json!(p.1) } else { json!(p.0) }
+                        }
+                        _ => scrub(field),
+                    },
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(scrub),
+        _ => {}
+    }
+}
+
+fn scrub_all(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                // Keep the names of appended fields, they are not personal
+                if name != "FieldName" {
+                    scrub_all(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(scrub_all),
+        Value::String(s) => *s = "000000000".to_string(),
+        Value::Number(_) => *value = json!(0),
+        _ => {}
+    }
+}
+
+/// The string and numeric placeholders for personal fields
+fn placeholder(field: &str) -> Option<(&'static str, u64)> {
+    let p = match field {
+        "FirstGivenName" => ("Jane", 0),
+        "MiddleName" => ("Q", 0),
+        "FirstSurName" => ("Doe", 0),
+        "ISOLatin1Name" | "FullName" => ("Jane Doe", 0),
+        "DayOfBirth" | "MonthOfBirth" => ("1", 1),
+        "YearOfBirth" => ("1980", 1980),
+        "BuildingNumber" | "UnitNumber" => ("1", 1),
+        "BuildingName" | "POBox" => ("", 0),
+        "StreetName" => ("Main", 0),
+        "StreetType" => ("St", 0),
+        "City" | "Suburb" => ("Springfield", 0),
+        "PostalCode" => ("00000", 0),
+        "Address1" => ("1 Main St", 0),
+        "Telephone" | "Telephone2" | "MobileNumber" => ("5550100", 5_550_100),
+        "EmailAddress" => ("jane.doe@example.com", 0),
+        "Number" => ("000000000", 0),
+        "Mrz1" | "Mrz2" => ("", 0),
+        _ => return None,
+    };
+    Some(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrub_replaces_personal_data() {
+        let mut value = json!([{
+            "PersonInfo": { "FirstGivenName": "Alice", "YearOfBirth": 1971, "Gender": "F" },
+            "Passport": { "Number": "X1234567", "Mrz1": null },
+            "CountrySpecific": { "US": { "SocialSecurityNumber": "123-45-6789" } }
+        }]);
+        scrub(&mut value);
+        assert_eq!(
+            value,
+            json!([{
+                "PersonInfo": { "FirstGivenName": "Jane", "YearOfBirth": 1980, "Gender": "F" },
+                "Passport": { "Number": "000000000", "Mrz1": null },
+                "CountrySpecific": { "US": { "SocialSecurityNumber": "000000000" } }
+            }])
+        );
+        assert_eq!(fixture_name("GET", "/configuration/v1/testentities/Identity%20Verification/US"), Some("testentities/US.json".to_string()));
+        assert_eq!(fixture_name("GET", "/configuration/v1/testentities/Identity%20Verification/..%2F"), None);
+    }
+}
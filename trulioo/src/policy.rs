@@ -0,0 +1,209 @@
+use crate::TruliooError;
+use rand::Rng;
+use serde::Serialize;
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long to wait for Trulioo and what to do when it fails
+#[derive(Clone, Debug)]
+pub struct ClientPolicy {
+    pub connect_timeout: Duration,
+    /// The whole request including reading the body
+    pub timeout: Duration,
+    /// Extra attempts for GETs. POSTs are never retried, a verification may
+    /// have gone through even though the response was lost.
+    pub max_retries: u32,
+    /// Retry delays are random up to `backoff_base * 2^attempt`, capped at
+    /// `backoff_max`
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// Consecutive failures before calls fail fast
+    pub failure_threshold: u32,
+    /// How long calls fail fast before one is let through to test the water
+    pub open_duration: Duration,
+}
+
+impl ClientPolicy {
+    /// Delay before retry number `attempt`, counting from zero
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .backoff_base
+            .checked_mul(1u32.checked_shl(attempt).unwrap_or(u32::max_value()))
+            .unwrap_or(self.backoff_max)
+            .min(self.backoff_max);
+        let millis = ceiling.as_millis() as u64;
+        if millis == 0 {
+            return ceiling;
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(0, millis + 1))
+    }
+}
+
+impl Default for ClientPolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            max_retries: 2,
+            backoff_base: Duration::from_millis(200),
+            backoff_max: Duration::from_secs(5),
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+pub enum CircuitState {
+    /// Calls go through
+    #[serde(rename = "closed")]
+    Closed,
+    /// Trulioo looks down, calls fail fast
+    #[serde(rename = "open")]
+    Open,
+    /// A trial call is deciding whether to close again
+    #[serde(rename = "half-open")]
+    HalfOpen,
+}
+
+/// A snapshot of the circuit breaker for health checks
+#[derive(Clone, Debug, Serialize)]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    /// When the next trial call is allowed, while open
+    pub retry_at: Option<u64>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    opened_at: Option<SystemTime>,
+    trial_in_flight: bool,
+}
+
+/// Stops hammering Trulioo while it is down and fails fast instead of
+/// tying up workers until the timeout
+pub(crate) struct CircuitBreaker {
+    threshold: u32,
+    open_duration: Duration,
+    state: Mutex<Breaker>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: &ClientPolicy) -> Self {
+        Self {
+            threshold: policy.failure_threshold.max(1),
+            open_duration: policy.open_duration,
+            state: Mutex::new(Breaker::default()),
+        }
+    }
+
+    /// Err while open, lets one call through once `open_duration` passes
+    pub fn allow(&self) -> Result<(), TruliooError> {
+        let mut state = self.state.lock().unwrap();
+        let opened_at = match state.opened_at {
+            None => return Ok(()),
+            Some(o) => o,
+        };
+        let elapsed = opened_at.elapsed().unwrap_or_default();
+        if elapsed < self.open_duration || state.trial_in_flight {
+            let retry_after = self.open_duration.checked_sub(elapsed).unwrap_or_default();
+            return Err(TruliooError::CircuitOpen { retry_after: retry_after.as_secs() });
+        }
+        state.trial_in_flight = true;
+        Ok(())
+    }
+
+    pub fn record<T>(&self, result: &Result<T, TruliooError>) {
+        let mut state = self.state.lock().unwrap();
+        state.trial_in_flight = false;
+        match result {
+            Err(e) if e.is_outage() => {
+                state.failures = state.failures.saturating_add(1);
+                if state.failures >= self.threshold {
+                    state.opened_at = Some(SystemTime::now());
+                }
+            }
+            _ => {
+                state.failures = 0;
+                state.opened_at = None;
+            }
+        }
+    }
+
+    pub fn status(&self) -> CircuitStatus {
+        let state = self.state.lock().unwrap();
+        let retry_at = state.opened_at.map(|o| o + self.open_duration);
+        let state_name = match retry_at {
+            None => CircuitState::Closed,
+            Some(r) if r > SystemTime::now() => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        };
+        CircuitStatus {
+            state: state_name,
+            consecutive_failures: state.failures,
+            retry_at: retry_at.and_then(|r| r.duration_since(UNIX_EPOCH).ok()).map(|r| r.as_secs()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaker_opens_and_recovers() {
+        let policy = ClientPolicy { failure_threshold: 2, open_duration: Duration::from_millis(50), ..ClientPolicy::default() };
+        let breaker = CircuitBreaker::new(&policy);
+        let down: Result<(), TruliooError> = Err(TruliooError::Transport("timed out".to_string()));
+
+        breaker.record(&down);
+        assert!(breaker.allow().is_ok());
+        breaker.record(&down);
+        assert_eq!(breaker.status().state, CircuitState::Open);
+        assert!(breaker.allow().is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.allow().is_err(), "only one trial call at a time");
+        breaker.record(&Ok(()));
+        assert_eq!(breaker.status().state, CircuitState::Closed);
+
+        for attempt in 0..40 {
+            assert!(policy.backoff(attempt) <= policy.backoff_max);
+        }
+    }
+
+    #[test]
+    fn half_open_trial_decides() {
+        let policy = ClientPolicy { failure_threshold: 1, open_duration: Duration::from_millis(50), ..ClientPolicy::default() };
+        let breaker = CircuitBreaker::new(&policy);
+        let down: Result<(), TruliooError> = Err(TruliooError::Server { status: 503, body: String::new() });
+
+        // Errors that are the caller's fault do not open the circuit
+        breaker.record::<()>(&Err(TruliooError::Api { code: None, message: "bad".to_string() }));
+        breaker.record::<()>(&Err(TruliooError::RateLimited { retry_after: None }));
+        assert_eq!(breaker.status().state, CircuitState::Closed);
+
+        breaker.record(&down);
+        assert!(breaker.allow().is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(breaker.status().state, CircuitState::HalfOpen);
+
+        // A failed trial opens the circuit for another open_duration
+        assert!(breaker.allow().is_ok());
+        breaker.record(&down);
+        assert_eq!(breaker.status().state, CircuitState::Open);
+        assert!(breaker.allow().is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow().is_ok());
+        breaker.record(&Ok(()));
+        assert_eq!(breaker.status().state, CircuitState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 0);
+        assert!(breaker.allow().is_ok());
+    }
+}
@@ -3,11 +3,7 @@ extern crate trace_macros;
 
 use isahc::prelude::*;
 use serde::{Serialize, Deserialize};
-use sha2::Digest;
-use sodiumoxide::crypto::sign::{
-    sign_detached, gen_keypair,
-    ed25519::SecretKey
-};
+use sovrin_signing::{MessageFormat, Scheme, SignatureEncoding};
 use structopt::StructOpt;
 use web_view::*;
 
@@ -26,8 +22,15 @@ struct Opt {
 enum Command {
     #[structopt(name = "sign")]
     Sign {
+        /// base58 signing key, with or without a checksum
         #[structopt(short, long)]
         key: Option<String>,
+        /// prefixed-sha256 or raw
+        #[structopt(short, long, default_value = "prefixed-sha256")]
+        message: MessageFormat,
+        /// base64url, base64 or hex
+        #[structopt(short, long, default_value = "base64url")]
+        encoding: SignatureEncoding,
         #[structopt(name = "TOKEN")]
         token: String
     }
@@ -37,7 +40,8 @@ enum Command {
 struct PaymentAddressChallengeReponse {
     address: String,
     challenge: String,
-    signature: String
+    signature: String,
+    scheme: Scheme
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -279,29 +283,26 @@ fn main() {
 
     let opt = Opt::from_args();
     match opt.cmd {
-        Command::Sign { key, token } => {
-            let (pk, sk) = match key {
-                Some(k) => {
-                    let k1 = bs58::decode(k).into_vec().unwrap();
-                    let sk1 = SecretKey::from_slice(k1.as_slice()).unwrap();
-                    let pk1 = sk1.public_key();
-                    (pk1, sk1)
+        Command::Sign { key, message, encoding, token } => {
+            let sk = match key {
+                // Keys printed before checksums were added have none
+                Some(k) => match bs58::decode(&k).with_check(None).into_vec().or_else(|_| bs58::decode(&k).into_vec()) {
+                    Err(why) => panic!("Unable to decode the signing key: {}", why),
+                    Ok(sk) => sk
                 },
-                None => gen_keypair()
+                None => sovrin_signing::generate_signing_key()
             };
-            let mut sha = sha2::Sha256::new();
+            let pk = sovrin_signing::verkey(&sk).unwrap();
+            let scheme = Scheme { message, encoding };
 
             let challenge = base64_url::decode(&token).unwrap();
-
-            sha.input(format!("\x6DSovrin Signed Message:\nLength: {}\n", challenge.len()).as_bytes());
-            sha.input(challenge.as_slice());
-            let data = sha.result();
-            let signature = sign_detached(data.as_slice(), &sk);
+            let signature = sovrin_signing::sign_challenge(&sk, &challenge, scheme).unwrap();
 
             let response = PaymentAddressChallengeReponse {
-                address: format!("pay:sov:{}", bs58::encode(&pk[..]).with_check().into_string()),
+                address: sovrin_signing::encode_payment_address(&pk).unwrap(),
                 challenge: token,
-                signature: base64_url::encode(&signature[..])
+                signature,
+                scheme
             };
 
             println!("key = {}", bs58::encode(sk).with_check().into_string());
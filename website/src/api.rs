@@ -0,0 +1,192 @@
+use rocket::{
+    http::Status,
+    request::Request,
+    response::{self, Responder, Response},
+};
+use rocket_contrib::json::Json;
+use serde::Serialize;
+use trulioo::TruliooError;
+
+/// What every route returns: `{ "status": "success", "result": ... }` on
+/// success or `{ "status": "error", "message": ... }` with an error status
+pub(crate) type ApiResult<T> = Result<ApiResponse<T>, ApiError>;
+
+#[derive(Debug)]
+pub(crate) struct ApiResponse<T>(pub T);
+
+impl<T: Serialize> ApiResponse<T> {
+    pub fn ok(result: T) -> ApiResult<T> {
+        Ok(ApiResponse(result))
+    }
+}
+
+#[derive(Serialize)]
+struct SuccessBody<T> {
+    status: &'static str,
+    result: T,
+}
+
+impl<'r, T: Serialize> Responder<'r> for ApiResponse<T> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = SuccessBody { status: "success", result: self.0 };
+        Response::build_from(Json(body).respond_to(request)?)
+            .status(Status::Ok)
+            .ok()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ApiError {
+    pub status: Status,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    status: &'static str,
+    message: &'a str,
+}
+
+impl ApiError {
+    pub fn new<S: Into<String>>(status: Status, message: S) -> Self {
+        Self { status, message: message.into() }
+    }
+
+    /// The request itself is wrong
+    pub fn bad_request<S: Into<String>>(message: S) -> Self {
+        Self::new(Status::BadRequest, message)
+    }
+
+    pub fn not_found<S: Into<String>>(message: S) -> Self {
+        Self::new(Status::NotFound, message)
+    }
+
+    /// The request is fine but the application is not ready for it
+    pub fn conflict<S: Into<String>>(message: S) -> Self {
+        Self::new(Status::Conflict, message)
+    }
+
+    /// Something went wrong here
+    pub fn internal<S: Into<String>>(message: S) -> Self {
+        Self::new(Status::InternalServerError, message)
+    }
+
+    /// An upstream service like Trulioo failed
+    pub fn bad_gateway<S: Into<String>>(message: S) -> Self {
+        Self::new(Status::BadGateway, message)
+    }
+
+    pub fn service_unavailable<S: Into<String>>(message: S) -> Self {
+        Self::new(Status::ServiceUnavailable, message)
+    }
+}
+
+impl From<TruliooError> for ApiError {
+    fn from(e: TruliooError) -> Self {
+        match e {
+            // Trulioo does not like what the user sent
+            TruliooError::Api { code: Some(_), .. } | TruliooError::InvalidRequest(_) => ApiError::bad_request(e.to_string()),
+            TruliooError::RateLimited { .. } => ApiError::service_unavailable("Identity verification is busy, try again shortly"),
+            TruliooError::CircuitOpen { .. } => ApiError::service_unavailable("Identity verification is temporarily unavailable"),
+            // Our key, nothing the user can do about it
+            TruliooError::Unauthorized => ApiError::internal("Identity verification is misconfigured"),
+            // Raw Trulioo responses can echo the personal data that was sent,
+            // they only go to the log
+            TruliooError::Api { code: None, .. } => {
+                log::error!("Trulioo: {}", e);
+                ApiError::bad_request("Identity verification rejected the request")
+            }
+            TruliooError::Transport(_) | TruliooError::Server { .. } | TruliooError::Decode { .. } => {
+                log::error!("Trulioo: {}", e);
+                ApiError::bad_gateway("Identity verification failed upstream")
+            }
+        }
+    }
+}
+
+/// Errors Rocket raises before a route runs, e.g. an unknown path or a body
+/// that does not parse, get the same envelope as route errors
+#[catch(400)]
+pub(crate) fn bad_request(_: &Request) -> ApiError {
+    ApiError::bad_request("The request could not be understood")
+}
+
+#[catch(404)]
+pub(crate) fn not_found(request: &Request) -> ApiError {
+    ApiError::not_found(format!("Nothing is at {}", request.uri().path()))
+}
+
+#[catch(422)]
+pub(crate) fn unprocessable_entity(_: &Request) -> ApiError {
+    ApiError::new(Status::UnprocessableEntity, "The request body is malformed or missing fields")
+}
+
+#[catch(500)]
+pub(crate) fn internal_error(_: &Request) -> ApiError {
+    ApiError::internal("Something went wrong")
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = ErrorBody { status: "error", message: &self.message };
+        Response::build_from(Json(body).respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{http::ContentType, local::Client};
+    use serde_json::Value;
+
+    #[derive(serde::Deserialize)]
+    struct Body {
+        value: u64,
+    }
+
+    #[post("/echo", format = "application/json", data = "<body>")]
+    fn echo(body: Json<Body>) -> ApiResult<u64> {
+        ApiResponse::ok(body.into_inner().value)
+    }
+
+    #[test]
+    fn upstream_responses_are_not_passed_on() {
+        let server = ApiError::from(TruliooError::Server { status: 500, body: "Jane Doe 1980".to_string() });
+        let decode = ApiError::from(TruliooError::Decode { body: "Jane Doe 1980".to_string(), reason: "Jane".to_string() });
+        let api = ApiError::from(TruliooError::Api { code: None, message: "Jane Doe 1980".to_string() });
+        for error in &[server, decode, api] {
+            assert!(!error.message.contains("Jane"), "{}", error.message);
+        }
+        assert_eq!(ApiError::from(TruliooError::Unauthorized).status, Status::InternalServerError);
+    }
+
+    #[test]
+    fn rocket_errors_are_json() {
+        let rocket = rocket::ignite()
+            .mount("/", routes![echo])
+            .register(catchers![bad_request, not_found, unprocessable_entity, internal_error]);
+        let client = Client::new(rocket).unwrap();
+
+        let mut response = client.get("/missing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(body["status"], "error");
+
+        let mut response = client.post("/echo").header(ContentType::JSON).body("{\"value\": \"x\"}").dispatch();
+        assert!(response.status() == Status::UnprocessableEntity || response.status() == Status::BadRequest);
+        let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(body["status"], "error");
+
+        let mut response = client.post("/echo").header(ContentType::JSON).body("{\"value\": 1}").dispatch();
+        let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(body["result"], 1);
+    }
+}
@@ -0,0 +1,43 @@
+use crate::{
+    api::{ApiError, ApiResponse, ApiResult},
+    store::{Application, Store},
+};
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub(crate) struct ConsentsAccepted {
+    pub consents: Vec<String>
+}
+
+#[post("/applications")]
+pub(crate) fn create_application(store: State<Store>) -> ApiResult<Application> {
+    store.inner().create_application().map(ApiResponse).map_err(ApiError::internal)
+}
+
+#[get("/applications/<id>")]
+pub(crate) fn get_application(id: String, store: State<Store>) -> ApiResult<Application> {
+    store.inner().get_application(&id)
+        .map_err(ApiError::internal)?
+        .map(ApiResponse)
+        .ok_or_else(|| ApiError::not_found("Unknown application"))
+}
+
+#[post("/applications/<id>/consents", format = "application/json", data = "<accepted>")]
+pub(crate) fn accept_consents(id: String, accepted: Json<ConsentsAccepted>, store: State<Store>) -> ApiResult<Application> {
+    let accepted = accepted.into_inner();
+    if accepted.consents.iter().any(|c| c.trim().is_empty()) {
+        return Err(ApiError::bad_request("Consents cannot be empty"));
+    }
+    store.inner().get_application(&id)
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found("Unknown application"))?;
+    store.inner().update_application(&id, |a| {
+        for consent in &accepted.consents {
+            if !a.consents.contains(consent) {
+                a.consents.push(consent.clone());
+            }
+        }
+    }).map(ApiResponse).map_err(ApiError::internal)
+}
@@ -0,0 +1,166 @@
+use crate::{
+    api::{ApiError, ApiResponse, ApiResult},
+    store::{Application, Store},
+};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature};
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sovrin_signing::AddressFormat;
+
+/// Seconds an attestation can be presented for after the proof
+pub(crate) const MAX_AGE: u64 = 24 * 60 * 60;
+
+/// Statement that the holder of `address` signed one of our challenges
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Attestation {
+    pub address: String,
+    /// The application the address was proven for, if any
+    pub application_id: Option<String>,
+    pub verified_at: u64,
+    /// Hex sha256 of the challenge bytes the wallet signed
+    pub challenge_hash: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AttestationRequest {
+    pub attestation: String,
+}
+
+/// What `verify_payment_address_challenge` returns
+#[derive(Serialize)]
+pub(crate) struct AddressProof {
+    pub verified: bool,
+    /// The address in canonical `pay:sov:` form
+    pub address: String,
+    /// How the address was written in the request
+    pub format: AddressFormat,
+    /// Present when verified, hand it to the KYC and order endpoints or to
+    /// anyone else who needs to know the address was proven
+    pub attestation: Option<String>,
+}
+
+/// Signs attestations as `base64url(json).base64url(ed25519 signature)`
+pub(crate) struct Attestor {
+    keypair: Keypair,
+}
+
+impl Attestor {
+    pub fn new(key: Option<&String>) -> Result<Self, String> {
+        let key = match key {
+            Some(k) => base64_url::decode(k).map_err(|e| format!("Invalid attestation signing key: {}", e))?,
+            None => base64_url::decode(&crate::config::Keys::generate_key()).unwrap(),
+        };
+        let secret = SecretKey::from_bytes(&key).map_err(|e| format!("Invalid attestation signing key: {}", e))?;
+        let public = PublicKey::from(&secret);
+        Ok(Self { keypair: Keypair { secret, public } })
+    }
+
+    /// The key attestations verify against, encoded like a verkey
+    pub fn public_key(&self) -> String {
+        bs58::encode(self.keypair.public.as_bytes()).into_string()
+    }
+
+    pub fn issue(&self, address: &str, application_id: Option<String>, challenge: &[u8]) -> Result<String, String> {
+        self.issue_at(address, application_id, challenge, crate::generate_timestamp()?)
+    }
+
+    fn issue_at(&self, address: &str, application_id: Option<String>, challenge: &[u8], verified_at: u64) -> Result<String, String> {
+        let attestation = Attestation {
+            address: address.to_string(),
+            application_id,
+            verified_at,
+            challenge_hash: hex::encode(Sha256::digest(challenge)),
+        };
+        let payload = serde_json::to_vec(&attestation).map_err(|e| e.to_string())?;
+        let signature = self.keypair.sign(&payload);
+        Ok(format!("{}.{}", base64_url::encode(&payload), base64_url::encode(&signature.to_bytes()[..])))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Attestation, String> {
+        let invalid = || "Invalid attestation".to_string();
+        let mut parts = token.splitn(2, '.');
+        let (payload, signature) = match (parts.next(), parts.next()) {
+            (Some(p), Some(s)) => (p, s),
+            _ => return Err(invalid()),
+        };
+        let payload = base64_url::decode(payload).map_err(|_| invalid())?;
+        let signature = base64_url::decode(signature).map_err(|_| invalid())?;
+        let signature = Signature::from_bytes(&signature).map_err(|_| invalid())?;
+        self.keypair.public.verify(&payload, &signature).map_err(|_| invalid())?;
+        serde_json::from_slice(&payload).map_err(|_| invalid())
+    }
+
+    /// Accept an attestation as proof of the application's payment address,
+    /// recording the address if none was proven yet. Attestations are shown
+    /// to others so only ones issued for this application in the last
+    /// `MAX_AGE` seconds are accepted.
+    pub fn present(&self, token: &str, application: &Application, store: &Store) -> Result<Application, ApiError> {
+        let attestation = self.verify(token).map_err(ApiError::bad_request)?;
+        if attestation.application_id.as_ref() != Some(&application.id) {
+            return Err(ApiError::conflict("The attestation is for a different application"));
+        }
+        let now = crate::generate_timestamp().map_err(ApiError::internal)?;
+        if attestation.verified_at.saturating_add(MAX_AGE) < now {
+            return Err(ApiError::conflict("The attestation has expired, prove the payment address again"));
+        }
+        match application.payment_address {
+            Some(ref a) if a != &attestation.address => Err(ApiError::conflict("The attestation is for a different payment address")),
+            Some(_) => Ok(application.clone()),
+            None => store.update_application(&application.id, |a| {
+                a.payment_address = Some(attestation.address.clone());
+                a.address_verified_at = Some(attestation.verified_at);
+            }).map_err(ApiError::internal),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct VerifiedAttestation {
+    pub attestation: Attestation,
+    /// The key it was checked against
+    pub public_key: String,
+}
+
+#[post("/attestations/verify", format = "application/json", data = "<request>")]
+pub(crate) fn verify_attestation(request: Json<AttestationRequest>, attestor: State<Attestor>) -> ApiResult<VerifiedAttestation> {
+    let attestation = attestor.inner().verify(&request.into_inner().attestation).map_err(ApiError::bad_request)?;
+    ApiResponse::ok(VerifiedAttestation { attestation, public_key: attestor.inner().public_key() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attestation_round_trip() {
+        let attestor = Attestor::new(None).unwrap();
+        let token = attestor.issue("pay:sov:address", Some("application".to_string()), b"challenge").unwrap();
+        let attestation = attestor.verify(&token).unwrap();
+        assert_eq!(attestation.address, "pay:sov:address");
+
+        let other = Attestor::new(None).unwrap();
+        assert!(other.verify(&token).is_err());
+    }
+
+    #[test]
+    fn attestations_are_bound_to_an_application() {
+        let attestor = Attestor::new(None).unwrap();
+        let store = Store::temporary().unwrap();
+        let application = store.create_application().unwrap();
+        let other = store.create_application().unwrap();
+        let now = crate::generate_timestamp().unwrap();
+
+        let unbound = attestor.issue("pay:sov:address", None, b"challenge").unwrap();
+        assert!(attestor.present(&unbound, &application, &store).is_err());
+        let elsewhere = attestor.issue("pay:sov:address", Some(other.id.clone()), b"challenge").unwrap();
+        assert!(attestor.present(&elsewhere, &application, &store).is_err());
+        let stale = attestor.issue_at("pay:sov:address", Some(application.id.clone()), b"challenge", now - MAX_AGE - 1).unwrap();
+        assert!(attestor.present(&stale, &application, &store).is_err());
+
+        let token = attestor.issue("pay:sov:address", Some(application.id.clone()), b"challenge").unwrap();
+        let presented = attestor.present(&token, &application, &store).unwrap();
+        assert_eq!(presented.payment_address, Some("pay:sov:address".to_string()));
+    }
+}
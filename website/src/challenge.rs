@@ -0,0 +1,271 @@
+use crate::{
+    config::{ChallengeMode, Challenges, Keys},
+    HmacSha256,
+};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature};
+use hmac::Mac;
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sovrin_signing::{CHALLENGE_VERSION, KEY_ID_LENGTH, NONCE_LENGTH};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+pub(crate) use sovrin_signing::{Challenge, Purpose};
+
+/// Identifies which key signed a challenge without revealing the key
+pub(crate) fn key_id(key: &[u8]) -> [u8; KEY_ID_LENGTH] {
+    let digest = Sha256::digest(key);
+    *array_ref!(digest.as_slice(), 0, KEY_ID_LENGTH)
+}
+
+enum Signer {
+    Hmac(Vec<u8>),
+    Ed25519(Keypair),
+}
+
+struct ChallengeKey {
+    id: [u8; KEY_ID_LENGTH],
+    signer: Signer,
+    /// Retired keys still verify challenges until this time
+    expires_at: Option<u64>,
+}
+
+impl ChallengeKey {
+    fn new(key: &str, mode: ChallengeMode, expires_at: Option<u64>) -> Result<Self, String> {
+        let key = base64_url::decode(key).map_err(|e| format!("Invalid challenge signing key: {}", e))?;
+        let (id, signer) = match mode {
+            ChallengeMode::Hmac => (key_id(&key), Signer::Hmac(key)),
+            ChallengeMode::Ed25519 => {
+                let secret = SecretKey::from_bytes(&key).map_err(|e| format!("Invalid challenge signing key: {}", e))?;
+                let public = PublicKey::from(&secret);
+                (key_id(public.as_bytes()), Signer::Ed25519(Keypair { secret, public }))
+            }
+        };
+        Ok(Self { id, signer, expires_at })
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match self.signer {
+            Signer::Hmac(ref key) => tag(key, data),
+            Signer::Ed25519(ref keypair) => keypair.sign(data).to_bytes().to_vec(),
+        }
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        match self.signer {
+            Signer::Hmac(ref key) => tag(key, data).ct_eq(signature).unwrap_u8() == 1,
+            Signer::Ed25519(ref keypair) => match Signature::from_bytes(signature) {
+                Ok(s) => keypair.public.verify(data, &s).is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+/// An ed25519 key third parties can check challenges against
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct PublishedKey {
+    /// Hex of the key id bytes in the challenge
+    pub key_id: String,
+    pub algorithm: &'static str,
+    /// base58
+    pub public_key: String,
+    /// Set on retired keys, they verify nothing issued after rotation
+    pub expires_at: Option<u64>,
+}
+
+/// Issues and checks challenges for this site. New challenges are signed
+/// with the active key, retired keys are kept for verification so rotating
+/// does not break challenges already handed out.
+pub(crate) struct ChallengeKeys {
+    domain: String,
+    lifetime: u64,
+    max_future_skew: u64,
+    active: ChallengeKey,
+    retired: Vec<ChallengeKey>,
+}
+
+/// A challenge handed out by the GET endpoint
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct IssuedChallenge {
+    /// Base64url of the challenge bytes
+    pub challenge: String,
+    /// When the challenge stops being accepted, so clients can refresh it
+    pub expires_at: u64,
+}
+
+impl ChallengeKeys {
+    /// Keys are interpreted according to `challenges.mode`, switching
+    /// modes invalidates challenges already handed out
+    pub fn new(keys: &Keys, domain: String, challenges: &Challenges) -> Result<Self, String> {
+        let active = ChallengeKey::new(&keys.challenge_signing_key, challenges.mode, None)?;
+        let mut retired = Vec::new();
+        for r in &keys.retired_challenge_keys {
+            retired.push(ChallengeKey::new(&r.key, challenges.mode, Some(r.expires_at))?);
+        }
+        Ok(Self {
+            domain,
+            lifetime: challenges.lifetime,
+            max_future_skew: challenges.max_future_skew,
+            active,
+            retired,
+        })
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// When a challenge issued at `issued_at` expires
+    pub fn expires_at(&self, issued_at: u64) -> u64 {
+        issued_at.saturating_add(self.lifetime)
+    }
+
+    pub fn issue(&self, purpose: Purpose, order_id: Option<String>) -> Result<IssuedChallenge, String> {
+        self.issue_at(purpose, order_id, crate::generate_timestamp()?)
+    }
+
+    fn issue_at(&self, purpose: Purpose, order_id: Option<String>, issued_at: u64) -> Result<IssuedChallenge, String> {
+        let mut rng = rand::rngs::OsRng{};
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rng.fill_bytes(&mut nonce);
+        let mut challenge = Challenge {
+            version: CHALLENGE_VERSION,
+            key_id: self.active.id,
+            issued_at,
+            nonce,
+            domain: self.domain.clone(),
+            purpose,
+            order_id,
+            tag: Vec::new(),
+        };
+        challenge.tag = self.active.sign(&challenge.unsigned_bytes().map_err(|e| e.to_string())?);
+        Ok(IssuedChallenge {
+            challenge: base64_url::encode(&challenge.to_bytes().map_err(|e| e.to_string())?),
+            expires_at: self.expires_at(issued_at),
+        })
+    }
+
+    /// Check the challenge came from this site, is current and decode it
+    pub fn verify(&self, bytes: &[u8]) -> Result<Challenge, String> {
+        let challenge = Challenge::parse(bytes).map_err(|e| e.to_string())?;
+        let now = crate::generate_timestamp()?;
+        let key = std::iter::once(&self.active)
+            .chain(self.retired.iter())
+            .find(|k| k.id == challenge.key_id)
+            .ok_or_else(|| "Challenge was signed with an unknown key".to_string())?;
+        if key.expires_at.map(|e| e < now).unwrap_or(false) {
+            return Err("Challenge was signed with a retired key".to_string());
+        }
+        if !key.verify(&bytes[..bytes.len() - challenge.tag.len()], &challenge.tag) {
+            return Err("Invalid challenge".to_string());
+        }
+        if challenge.domain != self.domain {
+            return Err("Challenge was issued for a different site".to_string());
+        }
+        if challenge.issued_at > now.saturating_add(self.max_future_skew) {
+            return Err("Challenge was issued in the future".to_string());
+        }
+        if self.expires_at(challenge.issued_at) < now {
+            return Err("Challenge has expired".to_string());
+        }
+        Ok(challenge)
+    }
+
+    /// The ed25519 keys challenges can be verified with, empty when
+    /// challenges are tagged with an HMAC
+    pub fn public_keys(&self) -> Vec<PublishedKey> {
+        std::iter::once(&self.active)
+            .chain(self.retired.iter())
+            .filter_map(|k| match k.signer {
+                Signer::Ed25519(ref keypair) => Some(PublishedKey {
+                    key_id: hex::encode(k.id),
+                    algorithm: "ed25519",
+                    public_key: bs58::encode(keypair.public.as_bytes()).into_string(),
+                    expires_at: k.expires_at,
+                }),
+                Signer::Hmac(_) => None,
+            })
+            .collect()
+    }
+}
+
+impl Drop for ChallengeKeys {
+    fn drop(&mut self) {
+        // ed25519 secret keys zero themselves
+        for k in std::iter::once(&mut self.active).chain(self.retired.iter_mut()) {
+            if let Signer::Hmac(ref mut key) = k.signer {
+                key.zeroize();
+            }
+        }
+    }
+}
+
+fn tag(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = HmacSha256::new_varkey(key).unwrap();
+    hmac.input(data);
+    hmac.result().code().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RetiredKey;
+    use sovrin_signing::TIMESTAMP_LENGTH;
+
+    fn bytes(issued: IssuedChallenge) -> Vec<u8> {
+        base64_url::decode(&issued.challenge).unwrap()
+    }
+
+    fn challenges(mode: ChallengeMode) -> Challenges {
+        Challenges { mode, ..Challenges::default() }
+    }
+
+    #[test]
+    fn challenge_round_trip() {
+        for &mode in &[ChallengeMode::Hmac, ChallengeMode::Ed25519] {
+            let keys = ChallengeKeys::new(&Keys::default(), "example.com".to_string(), &challenges(mode)).unwrap();
+            let bytes = bytes(keys.issue(Purpose::AuthorizeOrder, Some("order".to_string())).unwrap());
+            let challenge = keys.verify(&bytes).unwrap();
+            assert_eq!(challenge.purpose, Purpose::AuthorizeOrder);
+            assert_eq!(challenge.order_id, Some("order".to_string()));
+
+            let mut tampered = bytes.clone();
+            tampered[1 + KEY_ID_LENGTH + TIMESTAMP_LENGTH + NONCE_LENGTH + 1] ^= 1;
+            assert!(keys.verify(&tampered).is_err());
+            assert!(keys.verify(&bytes[..bytes.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn retired_key_verifies_until_expiry() {
+        let mut keys = Keys::default();
+        let domain = "example.com".to_string();
+        let old = ChallengeKeys::new(&keys, domain.clone(), &challenges(ChallengeMode::Hmac)).unwrap();
+        let challenge = bytes(old.issue(Purpose::ProveAddress, None).unwrap());
+
+        keys.rotate_challenge_key(60).unwrap();
+        let rotated = ChallengeKeys::new(&keys, domain.clone(), &challenges(ChallengeMode::Hmac)).unwrap();
+        assert!(rotated.verify(&challenge).is_ok());
+        assert_ne!(&bytes(rotated.issue(Purpose::ProveAddress, None).unwrap())[1..1 + KEY_ID_LENGTH], &challenge[1..1 + KEY_ID_LENGTH]);
+
+        keys.retired_challenge_keys = vec![RetiredKey { expires_at: 0, ..keys.retired_challenge_keys[0].clone() }];
+        let expired = ChallengeKeys::new(&keys, domain, &challenges(ChallengeMode::Hmac)).unwrap();
+        assert!(expired.verify(&challenge).is_err());
+    }
+
+    #[test]
+    fn challenges_are_only_accepted_within_their_lifetime() {
+        let config = Challenges { lifetime: 60, max_future_skew: 10, ..Challenges::default() };
+        let keys = ChallengeKeys::new(&Keys::default(), "example.com".to_string(), &config).unwrap();
+        let now = crate::generate_timestamp().unwrap();
+        let issue_at = |t| bytes(keys.issue_at(Purpose::ProveAddress, None, t).unwrap());
+
+        assert!(keys.verify(&issue_at(now - 50)).is_ok());
+        assert!(keys.verify(&issue_at(now + 5)).is_ok());
+        assert_eq!(keys.verify(&issue_at(now - 120)).unwrap_err(), "Challenge has expired");
+        assert_eq!(keys.verify(&issue_at(now + 60)).unwrap_err(), "Challenge was issued in the future");
+        assert_eq!(keys.expires_at(u64::max_value()), u64::max_value());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::reconcile::StatementFormat;
 use crate::secret_backend::SecretBackend;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -13,10 +14,14 @@ pub struct Opt {
     pub challenge_signing_key: Option<String>,
     #[structopt(short, long, parse(from_os_str))]
     pub config: Option<PathBuf>,
+    #[structopt(short, long, parse(from_os_str))]
+    pub database: Option<PathBuf>,
     #[structopt(short, long, default_value = "8000")]
     pub port: u16,
     #[structopt(short, long)]
     pub secretbackend: Option<SecretBackend>,
+    /// Run in sandbox mode against the Trulioo trial gateway, or recorded
+    /// fixtures without a Trulioo key, keeping test data in its own database
     #[structopt(short, long)]
     pub test: bool,
     #[structopt(short = "u", long)]
@@ -25,4 +30,27 @@ pub struct Opt {
     pub truliooapikeyname: Option<String>,
     #[structopt(short = "k", long)]
     pub truliooapikeyvalue: Option<String>,
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Match a bank statement's credits to open fiat orders
+    #[structopt(name = "import-statement")]
+    ImportStatement {
+        /// csv or camt053, guessed from the file extension when absent
+        #[structopt(short, long)]
+        format: Option<StatementFormat>,
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Replace the challenge signing key in the config file, challenges
+    /// signed with the old key stay valid for the grace period
+    #[structopt(name = "rotate-challenge-key")]
+    RotateChallengeKey {
+        /// Seconds the old key keeps verifying challenges
+        #[structopt(short, long, default_value = "3600")]
+        grace_period: u64,
+    },
 }
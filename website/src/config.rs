@@ -2,14 +2,36 @@ use crate::cmd_opt::Opt;
 use crate::secret_backend::SecretBackend;
 use rand::RngCore;
 use serde::{Serialize, Deserialize};
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 use zeroize::Zeroize;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
+    /// Where fiat payments are wired to, fiat orders are refused without it
+    pub bank: Option<BankAccount>,
+    pub challenges: Option<Challenges>,
+    /// Directory for the application database, defaults to ~/.token-website/db
+    pub database: Option<PathBuf>,
+    pub deposits: Option<Deposits>,
     pub keys: Keys,
     pub port: u16,
+    pub pricing: Option<Pricing>,
+    pub quotes: Option<Quotes>,
+    /// Set by --test, never saved
+    #[serde(skip)]
+    pub sandbox: bool,
+    /// Testnet keys used instead of `deposits` under --test, crypto orders
+    /// are refused in sandbox mode without them
+    pub sandbox_deposits: Option<Deposits>,
+    /// Trial gateway key used under --test, the recorded fixtures answer
+    /// without one
+    pub sandbox_trulioo: Option<Trulioo>,
+    /// Testnet nodes polled instead of `watcher` under --test
+    pub sandbox_watcher: Option<Watcher>,
     pub secret_backend: Option<SecretBackend>,
-    pub trulioo: Option<Trulioo>
+    pub trulioo: Option<Trulioo>,
+    pub trulioo_client: Option<TruliooClient>,
+    pub watcher: Option<Watcher>
 }
 
 impl Config {
@@ -52,17 +74,34 @@ impl Config {
             self.secret_backend = opt.secretbackend.clone();
         }
 
+        if opt.database.is_some() {
+            self.database = opt.database.clone();
+        }
+
         self.port = opt.port;
+        self.sandbox = opt.test;
     }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
+            bank: None,
+            challenges: None,
+            database: None,
+            deposits: None,
             keys: Keys::default(),
             port: 8000,
+            pricing: None,
+            quotes: None,
+            sandbox: false,
+            sandbox_deposits: None,
+            sandbox_trulioo: None,
+            sandbox_watcher: None,
             secret_backend: None,
-            trulioo: None
+            trulioo: None,
+            trulioo_client: None,
+            watcher: None
         }
     }
 }
@@ -89,15 +128,27 @@ impl From<&Opt> for Config {
                 if let Err(why) = base64_url::decode(c) {
                     panic!("Incompatible format for challenge signing key: {}", why);
                 }
-                Keys { challenge_signing_key: c.to_string() }
+                Keys { challenge_signing_key: c.to_string(), ..Keys::default() }
             } else {
                 Keys::default()
             };
         Config {
+            bank: None,
+            challenges: None,
+            database: opt.database.clone(),
+            deposits: None,
             keys,
             port: opt.port,
+            pricing: None,
+            quotes: None,
+            sandbox: opt.test,
+            sandbox_deposits: None,
+            sandbox_trulioo: None,
+            sandbox_watcher: None,
             secret_backend: opt.secretbackend,
-            trulioo
+            trulioo,
+            trulioo_client: None,
+            watcher: None
         }
     }
 }
@@ -109,18 +160,266 @@ pub struct Trulioo {
     pub url: String
 }
 
+/// Timeouts, retries and circuit breaking for calls to Trulioo
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Keys {
-    pub challenge_signing_key: String
+pub struct TruliooClient {
+    pub connect_timeout_secs: u64,
+    pub timeout_secs: u64,
+    /// Retries for GETs, verifications are never retried
+    pub max_retries: u32,
+    pub backoff_base_millis: u64,
+    pub backoff_max_millis: u64,
+    /// Consecutive failures before calls fail fast
+    pub failure_threshold: u32,
+    /// Seconds to fail fast before trying Trulioo again
+    pub open_secs: u64,
+    /// e.g. http://proxy.example.com:3128 for deployments behind a proxy
+    pub proxy: Option<String>,
+    /// PEM file of CA certificates to trust instead of the system roots
+    pub ca_bundle: Option<PathBuf>,
+    pub user_agent: Option<String>,
+    /// Cap on open connections to Trulioo
+    pub max_connections: Option<usize>
 }
 
-impl Default for Keys {
+impl Default for TruliooClient {
+    fn default() -> Self {
+        Self::from(&trulioo::ClientPolicy::default())
+    }
+}
+
+impl From<&trulioo::ClientPolicy> for TruliooClient {
+    fn from(policy: &trulioo::ClientPolicy) -> Self {
+        Self {
+            connect_timeout_secs: policy.connect_timeout.as_secs(),
+            timeout_secs: policy.timeout.as_secs(),
+            max_retries: policy.max_retries,
+            backoff_base_millis: policy.backoff_base.as_millis() as u64,
+            backoff_max_millis: policy.backoff_max.as_millis() as u64,
+            failure_threshold: policy.failure_threshold,
+            open_secs: policy.open_duration.as_secs(),
+            proxy: None,
+            ca_bundle: None,
+            user_agent: None,
+            max_connections: None
+        }
+    }
+}
+
+impl From<&TruliooClient> for trulioo::ClientPolicy {
+    fn from(client: &TruliooClient) -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(client.connect_timeout_secs),
+            timeout: Duration::from_secs(client.timeout_secs),
+            max_retries: client.max_retries,
+            backoff_base: Duration::from_millis(client.backoff_base_millis),
+            backoff_max: Duration::from_millis(client.backoff_max_millis),
+            failure_threshold: client.failure_threshold,
+            open_duration: Duration::from_secs(client.open_secs)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Pricing {
+    /// Price of one token in the minor unit of `currency` e.g. cents
+    pub token_price: u64,
+    pub currency: String,
+    pub minimum_tokens: u64,
+    pub maximum_tokens: u64
+}
+
+impl Default for Pricing {
+    fn default() -> Self {
+        Self {
+            token_price: 2,
+            currency: "USD".to_string(),
+            minimum_tokens: 1,
+            maximum_tokens: 1_000_000_000
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BankAccount {
+    pub beneficiary: String,
+    pub bank_name: String,
+    pub bank_address: Option<String>,
+    pub iban: Option<String>,
+    pub bic: Option<String>,
+    pub account_number: Option<String>,
+    pub routing_number: Option<String>
+}
+
+/// Payment address challenges
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Challenges {
+    /// The site named in challenges, defaults to localhost and the port
+    pub domain: Option<String>,
+    /// Record redeemed challenges in the database rather than in memory so
+    /// they stay single use across restarts
+    #[serde(default)]
+    pub persist_nonces: bool,
+    /// How challenges are signed, ed25519 lets anyone check them against
+    /// the keys published at /.well-known/challenge-keys
+    #[serde(default)]
+    pub mode: ChallengeMode,
+    /// Seconds a challenge can be answered after it is issued
+    #[serde(default = "Challenges::default_lifetime")]
+    pub lifetime: u64,
+    /// Seconds a challenge may appear to be issued in the future, to allow
+    /// for clocks drifting between servers sharing the signing key
+    #[serde(default = "Challenges::default_max_future_skew")]
+    pub max_future_skew: u64
+}
+
+impl Challenges {
+    fn default_lifetime() -> u64 {
+        3600
+    }
+
+    fn default_max_future_skew() -> u64 {
+        30
+    }
+}
+
+impl Default for Challenges {
     fn default() -> Self {
+        Self {
+            domain: None,
+            persist_nonces: false,
+            mode: ChallengeMode::default(),
+            lifetime: Challenges::default_lifetime(),
+            max_future_skew: Challenges::default_max_future_skew()
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ChallengeMode {
+    #[serde(rename = "hmac")]
+    Hmac,
+    #[serde(rename = "ed25519")]
+    Ed25519
+}
+
+impl Default for ChallengeMode {
+    fn default() -> Self {
+        ChallengeMode::Hmac
+    }
+}
+
+/// Extended public keys for deriving crypto deposit addresses. Use the
+/// external chain of an account e.g. m/84'/0'/0'/0 for bitcoin or
+/// m/44'/60'/0'/0 for ether, the private keys stay offline.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Deposits {
+    pub bitcoin_xpub: Option<String>,
+    /// Overrides the network encoded in the xpub e.g. regtest
+    pub bitcoin_network: Option<String>,
+    pub ether_xpub: Option<String>
+}
+
+/// Nodes to poll for payments to deposit addresses
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Watcher {
+    /// Seconds between polls
+    pub poll_interval: u64,
+    pub bitcoin: Option<NodeRpc>,
+    pub bitcoin_confirmations: u64,
+    pub ether: Option<NodeRpc>,
+    pub ether_confirmations: u64,
+    /// Seconds after an order is placed that payments to it are still
+    /// watched once it is paid, so late top-ups mark it overpaid
+    #[serde(default = "Watcher::default_overpayment_window")]
+    pub overpayment_window: u64
+}
+
+impl Watcher {
+    fn default_overpayment_window() -> u64 {
+        7 * 24 * 3600
+    }
+}
+
+/// A bitcoind or geth JSON-RPC endpoint
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NodeRpc {
+    pub url: String,
+    pub user: Option<String>,
+    pub password: Option<String>
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Quotes {
+    /// Seconds a crypto price quote stays valid
+    pub lifetime: u64,
+    /// A toml file of `CODE = rate` entries, re-read for every quote
+    pub rates_file: Option<PathBuf>,
+    /// Fixed rates used when there is no `rates_file`. Each rate is the
+    /// price of one whole coin in the minor unit of the pricing currency.
+    pub rates: Option<BTreeMap<String, u64>>
+}
+
+impl Default for Quotes {
+    fn default() -> Self {
+        Self {
+            lifetime: 900,
+            rates_file: None,
+            rates: None
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Keys {
+    /// Signs new payment address challenges
+    pub challenge_signing_key: String,
+    /// Previous challenge keys, only used to verify challenges issued
+    /// before a rotation
+    #[serde(default)]
+    pub retired_challenge_keys: Vec<RetiredKey>,
+    /// Generated at startup when absent, outstanding quotes then expire
+    /// with a restart
+    pub quote_signing_key: Option<String>,
+    /// ed25519 key for address attestations, generated at startup when
+    /// absent so attestations then stop verifying with a restart
+    pub attestation_signing_key: Option<String>
+}
+
+impl Keys {
+    pub fn generate_key() -> String {
         let mut rng = rand::rngs::OsRng{};
         let mut key = vec![0u8; 32];
         rng.fill_bytes(key.as_mut_slice());
-        let challenge_signing_key = base64_url::encode(&key);
+        let encoded = base64_url::encode(&key);
         key.zeroize();
-        Self { challenge_signing_key }
+        encoded
+    }
+
+    /// Replace the challenge signing key, the old one keeps verifying for
+    /// `grace_period` seconds. Retired keys past their expiry are dropped.
+    pub fn rotate_challenge_key(&mut self, grace_period: u64) -> Result<(), String> {
+        let now = crate::generate_timestamp()?;
+        self.retired_challenge_keys.retain(|r| r.expires_at >= now);
+        let key = std::mem::replace(&mut self.challenge_signing_key, Keys::generate_key());
+        self.retired_challenge_keys.push(RetiredKey { key, expires_at: now + grace_period });
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RetiredKey {
+    pub key: String,
+    pub expires_at: u64
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Self {
+            challenge_signing_key: Keys::generate_key(),
+            retired_challenge_keys: Vec::new(),
+            quote_signing_key: Some(Keys::generate_key()),
+            attestation_signing_key: Some(Keys::generate_key())
+        }
     }
 }
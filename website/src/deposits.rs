@@ -0,0 +1,137 @@
+use crate::{config::Deposits, quotes::CryptoCurrency};
+use bitcoin::{
+    secp256k1::{Secp256k1, VerifyOnly},
+    util::bip32::{ChildNumber, ExtendedPubKey},
+    Address, Network,
+};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tiny_keccak::{Hasher, Keccak};
+
+/// A receiving address allocated to one order
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct DepositAddress {
+    pub address: String,
+    /// The non-hardened child of the configured extended public key
+    pub index: u32,
+}
+
+/// Derives per-order receiving addresses from extended public keys so the
+/// private keys never need to be on the web host
+pub(crate) struct DepositAddresses {
+    bitcoin: Option<(ExtendedPubKey, Network)>,
+    ether: Option<ExtendedPubKey>,
+    secp: Secp256k1<VerifyOnly>,
+}
+
+impl DepositAddresses {
+    pub fn new(config: &Deposits) -> Result<Self, String> {
+        let bitcoin = match config.bitcoin_xpub {
+            Some(ref x) => {
+                let xpub = ExtendedPubKey::from_str(x).map_err(|e| format!("Invalid bitcoin xpub: {}", e))?;
+                let network = match config.bitcoin_network {
+                    Some(ref n) => parse_network(n)?,
+                    None => xpub.network,
+                };
+                Some((xpub, network))
+            }
+            None => None,
+        };
+        let ether = match config.ether_xpub {
+            Some(ref x) => Some(ExtendedPubKey::from_str(x).map_err(|e| format!("Invalid ether xpub: {}", e))?),
+            None => None,
+        };
+        Ok(Self {
+            bitcoin,
+            ether,
+            secp: Secp256k1::verification_only(),
+        })
+    }
+
+    pub fn supports(&self, currency: CryptoCurrency) -> bool {
+        match currency {
+            CryptoCurrency::Bitcoin => self.bitcoin.is_some(),
+            CryptoCurrency::Ether => self.ether.is_some(),
+        }
+    }
+
+    /// The network bitcoin addresses are encoded for
+    pub fn bitcoin_network(&self) -> Option<Network> {
+        self.bitcoin.as_ref().map(|&(_, network)| network)
+    }
+
+    pub fn derive(&self, currency: CryptoCurrency, index: u32) -> Result<DepositAddress, String> {
+        let child = [ChildNumber::from_normal_idx(index).map_err(|e| e.to_string())?];
+        let address = match currency {
+            CryptoCurrency::Bitcoin => {
+                let (xpub, network) = self
+                    .bitcoin
+                    .as_ref()
+                    .ok_or_else(|| "Bitcoin deposits are not configured".to_string())?;
+                let key = xpub.derive_pub(&self.secp, &child).map_err(|e| e.to_string())?;
+                Address::p2wpkh(&key.to_pub(), *network)
+                    .map_err(|e| e.to_string())?
+                    .to_string()
+            }
+            CryptoCurrency::Ether => {
+                let xpub = self
+                    .ether
+                    .as_ref()
+                    .ok_or_else(|| "Ether deposits are not configured".to_string())?;
+                let key = xpub.derive_pub(&self.secp, &child).map_err(|e| e.to_string())?;
+                ether_address(&key.public_key.serialize_uncompressed())
+            }
+        };
+        Ok(DepositAddress { address, index })
+    }
+}
+
+fn parse_network(network: &str) -> Result<Network, String> {
+    match network.to_lowercase().as_str() {
+        "bitcoin" | "mainnet" => Ok(Network::Bitcoin),
+        "testnet" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(format!("Unknown bitcoin network: {}", network)),
+    }
+}
+
+/// EIP-55 checksummed address of an uncompressed secp256k1 public key
+fn ether_address(public_key: &[u8; 65]) -> String {
+    let hash = keccak256(&public_key[1..]);
+    let hex_address = hex::encode(&hash[12..]);
+    let checksum = keccak256(hex_address.as_bytes());
+    let mut address = String::from("0x");
+    for (i, c) in hex_address.chars().enumerate() {
+        let nibble = (checksum[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0x0f;
+        if nibble >= 8 {
+            address.push(c.to_ascii_uppercase());
+        } else {
+            address.push(c);
+        }
+    }
+    address
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak::v256();
+    hasher.update(data);
+    let mut output = [0u8; 32];
+    hasher.finalize(&mut output);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ether_address_checksum() {
+        // Public key for private key 1, the generator point
+        let public_key = hex::decode("0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8").unwrap();
+        assert_eq!(
+            ether_address(array_ref!(public_key, 0, 65)),
+            "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf"
+        );
+    }
+}
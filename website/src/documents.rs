@@ -0,0 +1,313 @@
+use crate::{
+    api::{ApiError, ApiResponse, ApiResult},
+    kyc::{generate_reference_id, KycResult, KycStatus},
+    store::Store,
+};
+use celes::Country;
+use multipart::server::Multipart;
+use rocket::{
+    http::ContentType,
+    Data, State
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Read,
+    path::PathBuf,
+};
+use trulioo::{DataFields, Document, DocumentTypes, TruliooRequest, VerifyIdentityRequest};
+
+/// Matches the `forms` limit in Rocket.toml
+const MAX_REQUEST_SIZE: u64 = 32 * 1024 * 1024;
+const MAX_IMAGE_SIZE: u64 = 10 * 1024 * 1024;
+const MAX_TEXT_SIZE: u64 = 256;
+
+/// Where uploaded document images are kept
+pub(crate) struct DocumentStore {
+    pub root: PathBuf
+}
+
+impl DocumentStore {
+    pub fn new(root: PathBuf) -> Result<Self, String> {
+        fs::create_dir_all(&root).map_err(|e| format!("Unable to create {:?}: {}", root, e))?;
+        Ok(Self { root })
+    }
+
+    /// Images are kept per application so they can be found for audits and
+    /// deleted along with the application
+    fn save(&self, application_id: &str, reference: &str, side: &str, image: &Image) -> Result<PathBuf, String> {
+        let mut path = self.root.clone();
+        path.push(application_id);
+        path.push(reference);
+        fs::create_dir_all(&path).map_err(|e| e.to_string())?;
+        path.push(format!("{}.{}", side, image.format.extension()));
+        fs::write(&path, &image.data).map_err(|e| e.to_string())?;
+        Ok(path)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ImageFormat {
+    Jpeg,
+    Png
+}
+
+impl ImageFormat {
+    /// Identify the image by its magic bytes, not the client supplied content type
+    fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+            Some(ImageFormat::Png)
+        } else {
+            None
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png"
+        }
+    }
+}
+
+/// Accepts the names Trulioo uses as well as the labels on the KYC page
+fn parse_document_type(value: &str) -> Option<DocumentTypes> {
+    match value.trim().to_lowercase().as_str() {
+        "drivinglicence" | "driver's license" | "drivers license" | "driving licence" => Some(DocumentTypes::DrivingLicence),
+        "identitycard" | "national id" | "identity card" => Some(DocumentTypes::IdentityCard),
+        "passport" => Some(DocumentTypes::Passport),
+        "residencepermit" | "residence permit" => Some(DocumentTypes::ResidencePermit),
+        _ => None
+    }
+}
+
+struct Image {
+    format: ImageFormat,
+    data: Vec<u8>
+}
+
+#[derive(Default)]
+struct DocumentUpload {
+    transaction_id: Option<String>,
+    country: Option<String>,
+    document_type: Option<String>,
+    front: Option<Image>,
+    back: Option<Image>
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct DocumentResult {
+    pub transaction_id: String,
+    pub document: KycResult
+}
+
+#[post("/kyc/document", format = "multipart/form-data", data = "<data>")]
+pub(crate) fn verify_document(content_type: &ContentType, data: Data, request: State<TruliooRequest>, store: State<Store>, documents: State<DocumentStore>, countries: State<BTreeMap<String, Country>>) -> ApiResult<DocumentResult> {
+    let upload = read_upload(content_type, data).map_err(ApiError::bad_request)?;
+
+    let transaction_id = upload.transaction_id.ok_or_else(|| ApiError::bad_request("transaction_id is required"))?;
+    let application = store.inner().find_by_transaction(&transaction_id)
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found("Unknown transaction_id"))?;
+    match application.individual {
+        Some(ref r) if r.status == KycStatus::Match && r.transaction_id.as_ref() == Some(&transaction_id) => {},
+        _ => return Err(ApiError::conflict("Individual has not been verified"))
+    };
+
+    let country = match upload.country {
+        Some(c) if countries.inner().contains_key(&c) => c,
+        _ => return Err(ApiError::bad_request("Invalid country code"))
+    };
+    let document_type = upload.document_type
+        .and_then(|d| parse_document_type(&d))
+        .ok_or_else(|| ApiError::bad_request("Invalid document type"))?;
+    let front = upload.front.ok_or_else(|| ApiError::bad_request("document_upload_front is required"))?;
+
+    let reference = generate_reference_id();
+    documents.inner().save(&application.id, &reference, "front", &front).map_err(ApiError::internal)?;
+    if let Some(ref back) = upload.back {
+        documents.inner().save(&application.id, &reference, "back", back).map_err(ApiError::internal)?;
+    }
+
+    let verify_request = document_request(country, reference.clone(), application.consents.clone(), document_type, &front, upload.back.as_ref());
+    let response = async_std::task::block_on(request.inner().verify_document(&verify_request));
+    let document = KycResult::from(response?);
+    store.inner().update_application(&application.id, |a| {
+        a.document = Some(document.clone());
+        a.document_reference = Some(reference.clone());
+    }).map_err(ApiError::internal)?;
+    ApiResponse::ok(DocumentResult { transaction_id, document })
+}
+
+/// DocV only runs under the document verification configuration
+fn document_request(country: String, reference: String, consents: Vec<String>, document_type: DocumentTypes, front: &Image, back: Option<&Image>) -> VerifyIdentityRequest {
+    VerifyIdentityRequest {
+        accept_trulioo_terms_and_conditions: true,
+        configuration_name: trulioo::DOCUMENT_VERIFICATION.to_string(),
+        callback_url: None,
+        consent_for_data_sources: consents,
+        country_code: country,
+        customer_reference_id: reference,
+        datafields: DataFields {
+            person_info: None,
+            location: None,
+            communication: None,
+            driver_license: None,
+            national_ids: None,
+            passport: None,
+            business: None,
+            document: Some(Document {
+                front_image: base64::encode(&front.data),
+                back_image: back.map(|b| base64::encode(&b.data)),
+                live_photo: None,
+                document_type
+            }),
+            country_specific: None,
+        },
+        timeout: None,
+        cleansed_address: None,
+    }
+}
+
+fn read_upload(content_type: &ContentType, data: Data) -> Result<DocumentUpload, String> {
+    let boundary = content_type
+        .params()
+        .find(|&(k, _)| k == "boundary")
+        .map(|(_, v)| v.to_string())
+        .ok_or_else(|| "Missing multipart boundary".to_string())?;
+
+    let mut multipart = Multipart::with_body(data.open().take(MAX_REQUEST_SIZE), boundary);
+    let mut upload = DocumentUpload::default();
+    while let Some(mut field) = multipart.read_entry().map_err(|e| e.to_string())? {
+        let name = field.headers.name.to_string();
+        match name.as_str() {
+            "transaction_id" => upload.transaction_id = Some(read_text(&name, &mut field.data)?),
+            "country" => upload.country = Some(read_text(&name, &mut field.data)?),
+            "document_type" => upload.document_type = Some(read_text(&name, &mut field.data)?),
+            "document_upload_front" => upload.front = Some(read_image(&name, &mut field.data)?),
+            "document_upload_back" => upload.back = Some(read_image(&name, &mut field.data)?),
+            _ => return Err(format!("Unexpected field {}", name))
+        }
+    }
+    Ok(upload)
+}
+
+fn read_limited<R: Read>(name: &str, reader: R, limit: u64) -> Result<Vec<u8>, String> {
+    let mut buffer = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut buffer).map_err(|e| e.to_string())?;
+    if buffer.len() as u64 > limit {
+        return Err(format!("{} is too large", name));
+    }
+    Ok(buffer)
+}
+
+fn read_text<R: Read>(name: &str, reader: R) -> Result<String, String> {
+    let buffer = read_limited(name, reader, MAX_TEXT_SIZE)?;
+    String::from_utf8(buffer)
+        .map(|s| s.trim().to_string())
+        .map_err(|_| format!("{} is invalid", name))
+}
+
+fn read_image<R: Read>(name: &str, reader: R) -> Result<Image, String> {
+    let data = read_limited(name, reader, MAX_IMAGE_SIZE)?;
+    match ImageFormat::detect(&data) {
+        Some(format) => Ok(Image { format, data }),
+        None => Err(format!("{} must be a JPEG or PNG image", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{http::Status, local::Client};
+    use std::str::FromStr;
+    use trulioo::mock::MockServer;
+
+    const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0];
+    const BOUNDARY: &str = "document-upload-boundary";
+
+    fn multipart(fields: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n", BOUNDARY, name).as_bytes());
+            body.extend_from_slice(value);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    #[test]
+    fn uploads_are_checked() {
+        assert_eq!(ImageFormat::detect(&[0xFF, 0xD8, 0xFF, 0xE0]), Some(ImageFormat::Jpeg));
+        assert_eq!(ImageFormat::detect(PNG), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::detect(b"GIF89a"), None);
+        assert_eq!(ImageFormat::detect(&[0xFF, 0xD8]), None);
+        assert_eq!(ImageFormat::detect(&[]), None);
+
+        assert_eq!(read_limited("field", &b"12345"[..], 5).unwrap(), b"12345");
+        assert_eq!(read_limited("field", &b"123456"[..], 5).unwrap_err(), "field is too large");
+        assert!(read_limited("field", &b""[..], 0).unwrap().is_empty());
+        assert!(read_image("front", &b"not an image"[..]).is_err());
+    }
+
+    #[test]
+    fn document_requests_use_the_docv_configuration() {
+        let front = Image { format: ImageFormat::Png, data: PNG.to_vec() };
+        let consents = vec!["Birth Registry".to_string()];
+        let request = document_request("US".to_string(), "reference".to_string(), consents, DocumentTypes::Passport, &front, None);
+        let body: serde_json::Value = serde_json::from_str(&serde_json::to_string(&request).unwrap()).unwrap();
+        assert_eq!(body["ConfigurationName"], "Document Verification");
+        assert_eq!(body["ConsentForDataSources"], serde_json::json!(["Birth Registry"]));
+        assert_eq!(body["CustomerReferenceID"], "reference");
+        assert_eq!(body["DataFields"]["Document"]["DocumentFrontImage"], base64::encode(PNG));
+        assert!(body["DataFields"]["Document"]["DocumentBackImage"].is_null());
+
+        let root = PathBuf::from("/dev/null/documents");
+        assert!(DocumentStore::new(root).err().unwrap().contains("/dev/null/documents"));
+    }
+
+    #[test]
+    fn every_listed_document_type_is_accepted() {
+        let mock = MockServer::start(trulioo::mock::FIXTURES).unwrap();
+        let store = Store::temporary().unwrap();
+        let application = store.create_application().unwrap();
+        store.update_application(&application.id, |a| {
+            a.individual = Some(KycResult { status: KycStatus::Match, transaction_id: Some("transaction".to_string()), errors: Vec::new() });
+        }).unwrap();
+        let root = std::env::temp_dir().join(generate_reference_id());
+        let mut countries = BTreeMap::new();
+        countries.insert("US".to_string(), Country::from_str("US").unwrap());
+        let rocket = rocket::ignite()
+            .manage(mock.request())
+            .manage(store.clone())
+            .manage(DocumentStore::new(root.clone()).unwrap())
+            .manage(countries)
+            .mount("/", routes![verify_document]);
+        let client = Client::new(rocket).unwrap();
+
+        // The options of the documentList datalist in index.html
+        for document_type in &["Driver's License", "Passport", "National ID"] {
+            let body = multipart(&[
+                ("transaction_id", &b"transaction"[..]),
+                ("country", &b"US"[..]),
+                ("document_type", document_type.as_bytes()),
+                ("document_upload_front", PNG),
+            ]);
+            let mut response = client.post("/kyc/document")
+                .header(ContentType::with_params("multipart", "form-data", ("boundary", BOUNDARY)))
+                .body(body)
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok, "{}: {:?}", document_type, response.body_string());
+
+            let application = store.get_application(&application.id).unwrap().unwrap();
+            let reference = application.document_reference.unwrap();
+            assert!(root.join(&application.id).join(reference).join("front.png").is_file());
+        }
+        assert!(parse_document_type("Library Card").is_none());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,537 @@
+use crate::{
+    api::{ApiError, ApiResponse, ApiResult},
+    attestation::Attestor,
+    store::Store,
+};
+use celes::Country;
+use rand::RngCore;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use trulioo::{
+    Business, BusinessSearchRequest, BusinessSearchResult, Communication, DataFields, Gender,
+    Location, PersonInfo, TruliooRequest, VerifyIdentityRequest, VerifyIdentityResponse,
+};
+
+const MAX_FIELD_LENGTH: usize = 100;
+
+/// The personal information collected by the `enter_kyc_personal_info` page
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct IndividualKycRequest {
+    pub application_id: String,
+    pub first_given_name: String,
+    pub middle_name: Option<String>,
+    pub first_surname: String,
+    /// mm/dd/yyyy
+    pub date_of_birth: String,
+    pub gender: Option<String>,
+    pub building_number: Option<String>,
+    pub unit_number: Option<String>,
+    pub street_name: String,
+    pub street_type: Option<String>,
+    pub city: String,
+    pub state_province_code: Option<String>,
+    pub postal_code: String,
+    /// ISO 3166 alpha2 code
+    pub country: String,
+    pub email: String,
+    pub telephone: String,
+    #[serde(default)]
+    pub consents: Vec<String>,
+    /// Brings in an address proven before the application existed
+    #[serde(default)]
+    pub attestation: Option<String>,
+}
+
+impl IndividualKycRequest {
+    pub fn validate(&self, countries: &BTreeMap<String, Country>) -> Result<(), String> {
+        check_required("first_given_name", &self.first_given_name)?;
+        check_optional("middle_name", &self.middle_name)?;
+        check_required("first_surname", &self.first_surname)?;
+        parse_date("date_of_birth", &self.date_of_birth)?;
+        parse_gender(&self.gender)?;
+        check_optional("building_number", &self.building_number)?;
+        check_optional("unit_number", &self.unit_number)?;
+        check_required("street_name", &self.street_name)?;
+        check_optional("street_type", &self.street_type)?;
+        check_required("city", &self.city)?;
+        check_optional("state_province_code", &self.state_province_code)?;
+        check_required("postal_code", &self.postal_code)?;
+        check_email(&self.email)?;
+        check_telephone(&self.telephone)?;
+        if !countries.contains_key(&self.country) {
+            return Err("Invalid country code".to_string());
+        }
+        Ok(())
+    }
+
+    /// Person, address and contact fields for Identity Verification
+    pub fn to_verify_identity_request(&self, customer_reference_id: String) -> Result<VerifyIdentityRequest, String> {
+        let (month, day, year) = parse_date("date_of_birth", &self.date_of_birth)?;
+        let gender = parse_gender(&self.gender)?;
+        Ok(VerifyIdentityRequest {
+            accept_trulioo_terms_and_conditions: true,
+            configuration_name: trulioo::IDENTITY_VERIFICATION.to_string(),
+            callback_url: None,
+            consent_for_data_sources: self.consents.clone(),
+            country_code: self.country.clone(),
+            customer_reference_id,
+            datafields: DataFields {
+                person_info: Some(PersonInfo {
+                    first_given_name: Some(self.first_given_name.trim().to_string()),
+                    middle_name: trimmed(&self.middle_name),
+                    first_surname: Some(self.first_surname.trim().to_string()),
+                    day_of_birth: Some(day),
+                    month_of_birth: Some(month),
+                    year_of_birth: Some(year),
+                    iso_latin1_name: None,
+                    gender,
+                    minimum_age: None,
+                    additional_fields: None,
+                }),
+                location: Some(Location {
+                    building_number: trimmed(&self.building_number),
+                    building_name: None,
+                    unit_number: trimmed(&self.unit_number),
+                    street_name: Some(self.street_name.trim().to_string()),
+                    street_type: trimmed(&self.street_type),
+                    city: Some(self.city.trim().to_string()),
+                    suburb: None,
+                    state_province_code: trimmed(&self.state_province_code),
+                    postal_code: Some(self.postal_code.trim().to_string()),
+                    po_box: None,
+                    additional_fields: None,
+                }),
+                communication: Some(Communication {
+                    telephone: Some(self.telephone.trim().to_string()),
+                    telephone2: None,
+                    mobile_number: None,
+                    email_address: Some(self.email.trim().to_string()),
+                }),
+                driver_license: None,
+                national_ids: None,
+                passport: None,
+                business: None,
+                country_specific: None,
+            },
+            timeout: None,
+            cleansed_address: None,
+        })
+    }
+}
+
+/// The business information collected by the `enter_kyc_business_info` page
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct BusinessKycRequest {
+    /// The transaction id returned when the representative completed
+    /// individual verification
+    pub representative_transaction_id: String,
+    pub business_name: String,
+    pub business_registration_number: String,
+    pub duns_number: Option<String>,
+    /// mm/dd/yyyy
+    pub date_of_incorporation: String,
+    /// ISO 3166 alpha2 code
+    pub jurisdiction_country: String,
+    pub telephone: Option<String>,
+    pub building_number: Option<String>,
+    pub unit_number: Option<String>,
+    pub street_name: Option<String>,
+    pub street_type: Option<String>,
+    pub city: Option<String>,
+    pub state_province_code: Option<String>,
+    pub postal_code: Option<String>,
+    #[serde(default)]
+    pub consents: Vec<String>,
+}
+
+impl BusinessKycRequest {
+    pub fn validate(&self, countries: &BTreeMap<String, Country>) -> Result<(), String> {
+        check_required("representative_transaction_id", &self.representative_transaction_id)?;
+        check_required("business_name", &self.business_name)?;
+        check_required("business_registration_number", &self.business_registration_number)?;
+        if let Some(duns) = trimmed(&self.duns_number) {
+            if duns.len() != 9 || !duns.chars().all(|c| c.is_ascii_digit()) {
+                return Err("duns_number must be 9 digits".to_string());
+            }
+        }
+        parse_date("date_of_incorporation", &self.date_of_incorporation)?;
+        if let Some(telephone) = trimmed(&self.telephone) {
+            check_telephone(&telephone)?;
+        }
+        check_optional("building_number", &self.building_number)?;
+        check_optional("unit_number", &self.unit_number)?;
+        check_optional("street_name", &self.street_name)?;
+        check_optional("street_type", &self.street_type)?;
+        check_optional("city", &self.city)?;
+        check_optional("state_province_code", &self.state_province_code)?;
+        check_optional("postal_code", &self.postal_code)?;
+        if !countries.contains_key(&self.jurisdiction_country) {
+            return Err("Invalid country code".to_string());
+        }
+        Ok(())
+    }
+
+    /// The registration details checked by Business Verification
+    pub fn to_verify_business_request(&self, customer_reference_id: String) -> Result<VerifyIdentityRequest, String> {
+        let (month, day, year) = parse_date("date_of_incorporation", &self.date_of_incorporation)?;
+        Ok(VerifyIdentityRequest {
+            accept_trulioo_terms_and_conditions: true,
+            configuration_name: trulioo::BUSINESS_VERIFICATION.to_string(),
+            callback_url: None,
+            consent_for_data_sources: self.consents.clone(),
+            country_code: self.jurisdiction_country.clone(),
+            customer_reference_id,
+            datafields: DataFields {
+                person_info: None,
+                location: Some(Location {
+                    building_number: trimmed(&self.building_number),
+                    building_name: None,
+                    unit_number: trimmed(&self.unit_number),
+                    street_name: trimmed(&self.street_name),
+                    street_type: trimmed(&self.street_type),
+                    city: trimmed(&self.city),
+                    suburb: None,
+                    state_province_code: trimmed(&self.state_province_code),
+                    postal_code: trimmed(&self.postal_code),
+                    po_box: None,
+                    additional_fields: None,
+                }),
+                communication: Some(Communication {
+                    telephone: trimmed(&self.telephone),
+                    telephone2: None,
+                    mobile_number: None,
+                    email_address: None,
+                }),
+                driver_license: None,
+                national_ids: None,
+                passport: None,
+                business: Some(Business {
+                    name: Some(self.business_name.trim().to_string()),
+                    registration_number: Some(self.business_registration_number.trim().to_string()),
+                    duns_number: trimmed(&self.duns_number),
+                    day_of_incorporation: Some(day),
+                    month_of_incorporation: Some(month),
+                    year_of_incorporation: Some(year),
+                    jurisdiction_of_incorporation: Some(self.jurisdiction_country.clone()),
+                }),
+                country_specific: None,
+            },
+            timeout: None,
+            cleansed_address: None,
+        })
+    }
+}
+
+/// Looks a business up in the registries of its jurisdiction so the
+/// purchaser can pick the registration number to verify
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct BusinessSearchQuery {
+    pub business_name: String,
+    pub business_registration_number: Option<String>,
+    /// ISO 3166 alpha2 code
+    pub jurisdiction_country: String,
+    #[serde(default)]
+    pub consents: Vec<String>,
+}
+
+impl BusinessSearchQuery {
+    pub fn validate(&self, countries: &BTreeMap<String, Country>) -> Result<(), String> {
+        check_required("business_name", &self.business_name)?;
+        check_optional("business_registration_number", &self.business_registration_number)?;
+        if !countries.contains_key(&self.jurisdiction_country) {
+            return Err("Invalid country code".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn to_search_request(&self) -> BusinessSearchRequest {
+        BusinessSearchRequest {
+            accept_trulioo_terms_and_conditions: true,
+            configuration_name: trulioo::BUSINESS_SEARCH.to_string(),
+            callback_url: None,
+            consent_for_data_sources: self.consents.clone(),
+            country_code: self.jurisdiction_country.clone(),
+            business: Business {
+                name: Some(self.business_name.trim().to_string()),
+                registration_number: trimmed(&self.business_registration_number),
+                duns_number: None,
+                day_of_incorporation: None,
+                month_of_incorporation: None,
+                year_of_incorporation: None,
+                jurisdiction_of_incorporation: Some(self.jurisdiction_country.clone()),
+            },
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum KycStatus {
+    Match,
+    NoMatch,
+    Error,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct KycResult {
+    pub status: KycStatus,
+    pub transaction_id: Option<String>,
+    pub errors: Vec<String>,
+}
+
+impl From<VerifyIdentityResponse> for KycResult {
+    fn from(response: VerifyIdentityResponse) -> Self {
+        let mut errors = response
+            .errors
+            .iter()
+            .chain(response.record.errors.iter())
+            .map(|e| format!("{}: {}", e.code, e.message))
+            .collect::<Vec<String>>();
+        let status = match response.record.status.to_lowercase().as_str() {
+            "match" => KycStatus::Match,
+            "nomatch" => KycStatus::NoMatch,
+            s => {
+                errors.push(format!("Unexpected record status: {}", s));
+                KycStatus::Error
+            }
+        };
+        KycResult {
+            status,
+            transaction_id: Some(response.transaction_id),
+            errors,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct BusinessKycResult {
+    pub representative_transaction_id: String,
+    pub business: KycResult,
+}
+
+#[post("/kyc/individual", format = "application/json", data = "<info>")]
+pub(crate) fn verify_individual(info: Json<IndividualKycRequest>, request: State<TruliooRequest>, attestor: State<Attestor>, store: State<Store>, countries: State<BTreeMap<String, Country>>) -> ApiResult<KycResult> {
+    let info = info.into_inner();
+    info.validate(countries.inner()).map_err(ApiError::bad_request)?;
+
+    let application = store.inner().get_application(&info.application_id)
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found("Unknown application"))?;
+    if let Some(ref token) = info.attestation {
+        attestor.inner().present(token, &application, store.inner())?;
+    }
+
+    let verify_request = info.to_verify_identity_request(info.application_id.clone()).map_err(ApiError::bad_request)?;
+    let response = async_std::task::block_on(request.inner().verify_identity(&verify_request));
+    let result = KycResult::from(response?);
+    store.inner().update_application(&info.application_id, |a| a.individual = Some(result.clone()))
+        .map_err(ApiError::internal)?;
+    ApiResponse::ok(result)
+}
+
+#[post("/kyc/business", format = "application/json", data = "<info>")]
+pub(crate) fn verify_business(info: Json<BusinessKycRequest>, request: State<TruliooRequest>, store: State<Store>, countries: State<BTreeMap<String, Country>>) -> ApiResult<BusinessKycResult> {
+    let info = info.into_inner();
+    info.validate(countries.inner()).map_err(ApiError::bad_request)?;
+
+    let application = store.inner().find_by_transaction(&info.representative_transaction_id)
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found("Unknown representative"))?;
+    match application.individual {
+        Some(ref r) if r.status == KycStatus::Match && r.transaction_id.as_ref() == Some(&info.representative_transaction_id) => {},
+        _ => return Err(ApiError::conflict("Representative has not been verified"))
+    };
+
+    let verify_request = info.to_verify_business_request(application.id.clone()).map_err(ApiError::bad_request)?;
+    let response = async_std::task::block_on(request.inner().verify_business(&verify_request));
+    let business = KycResult::from(response?);
+    store.inner().update_application(&application.id, |a| a.business = Some(business.clone()))
+        .map_err(ApiError::internal)?;
+    ApiResponse::ok(BusinessKycResult {
+        representative_transaction_id: info.representative_transaction_id,
+        business
+    })
+}
+
+#[post("/kyc/business/search", format = "application/json", data = "<query>")]
+pub(crate) fn search_business(query: Json<BusinessSearchQuery>, request: State<TruliooRequest>, countries: State<BTreeMap<String, Country>>) -> ApiResult<Vec<BusinessSearchResult>> {
+    let query = query.into_inner();
+    query.validate(countries.inner()).map_err(ApiError::bad_request)?;
+
+    let response = async_std::task::block_on(request.inner().search_business(&query.to_search_request()));
+    let found = response?;
+    if let Some(e) = found.errors.iter().chain(found.record.errors.iter()).next() {
+        return Err(ApiError::bad_request(format!("{}: {}", e.code, e.message)));
+    }
+    ApiResponse::ok(found.record.data_source_results.into_iter().flat_map(|d| d.results).collect())
+}
+
+pub(crate) fn generate_reference_id() -> String {
+    let mut rng = rand::rngs::OsRng{};
+    let mut id = [0u8; 16];
+    rng.fill_bytes(&mut id);
+    base64_url::encode(&id)
+}
+
+fn trimmed(value: &Option<String>) -> Option<String> {
+    value
+        .as_ref()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+pub(crate) fn check_required(name: &str, value: &str) -> Result<(), String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(format!("{} cannot be empty", name));
+    }
+    if value.len() > MAX_FIELD_LENGTH || value.chars().any(|c| c.is_control()) {
+        return Err(format!("{} is invalid", name));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_optional(name: &str, value: &Option<String>) -> Result<(), String> {
+    match trimmed(value) {
+        Some(v) => check_required(name, &v),
+        None => Ok(()),
+    }
+}
+
+fn check_email(value: &str) -> Result<(), String> {
+    let value = value.trim();
+    let mut parts = value.split('@');
+    let valid = match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        }
+        _ => false,
+    };
+    if !valid || value.len() > MAX_FIELD_LENGTH || value.chars().any(char::is_whitespace) {
+        return Err("email is invalid".to_string());
+    }
+    Ok(())
+}
+
+pub(crate) fn check_telephone(value: &str) -> Result<(), String> {
+    let value = value.trim();
+    let digits = value.chars().filter(char::is_ascii_digit).count();
+    let allowed = value
+        .chars()
+        .all(|c| c.is_ascii_digit() || " +-().".contains(c));
+    if !allowed || digits < 6 || digits > 15 {
+        return Err("telephone is invalid".to_string());
+    }
+    Ok(())
+}
+
+fn parse_gender(value: &Option<String>) -> Result<Option<Gender>, String> {
+    match trimmed(value) {
+        None => Ok(None),
+        Some(v) => match v.to_lowercase().as_str() {
+            "m" | "male" => Ok(Some(Gender::Male)),
+            "f" | "female" => Ok(Some(Gender::Female)),
+            "not specified" => Ok(None),
+            _ => Err("gender is invalid".to_string()),
+        },
+    }
+}
+
+/// Parse a mm/dd/yyyy date into (month, day, year)
+pub(crate) fn parse_date(name: &str, value: &str) -> Result<(usize, usize, usize), String> {
+    let err = || format!("{} must be a valid date in the form mm/dd/yyyy", name);
+    let parts = value
+        .trim()
+        .split('/')
+        .map(|p| p.parse::<usize>().map_err(|_| err()))
+        .collect::<Result<Vec<usize>, String>>()?;
+    if parts.len() != 3 {
+        return Err(err());
+    }
+    let (month, day, year) = (parts[0], parts[1], parts[2]);
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return Err(err()),
+    };
+    let this_year = 1970 + (crate::generate_timestamp()? / 31_556_952) as usize;
+    if day == 0 || day > days_in_month || year < 1900 || year > this_year {
+        return Err(err());
+    }
+    Ok((month, day, year))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn individual() -> IndividualKycRequest {
+        IndividualKycRequest {
+            application_id: "application".to_string(),
+            first_given_name: "Jane".to_string(),
+            middle_name: None,
+            first_surname: "Doe".to_string(),
+            date_of_birth: "02/29/2000".to_string(),
+            gender: Some("F".to_string()),
+            building_number: None,
+            unit_number: None,
+            street_name: "Main".to_string(),
+            street_type: None,
+            city: "Provo".to_string(),
+            state_province_code: None,
+            postal_code: "84601".to_string(),
+            country: "US".to_string(),
+            email: "jane@example.com".to_string(),
+            telephone: "+1 (801) 555-0100".to_string(),
+            consents: Vec::new(),
+            attestation: None,
+        }
+    }
+
+    #[test]
+    fn validators() {
+        assert_eq!(parse_date("date", " 02/29/2000 ").unwrap(), (2, 29, 2000));
+        for bad in &["02/29/1900", "13/01/2000", "00/10/2000", "04/31/2000", "01/01/1899", "01/01/9999", "1/2", "a/b/c", "01/01/2000/1", ""] {
+            assert!(parse_date("date", bad).is_err(), "{}", bad);
+        }
+
+        assert!(check_email(" jane@example.com ").is_ok());
+        for bad in &["jane", "@example.com", "jane@example", "jane@.com", "jane@example.", "a@b@c.com", "ja ne@example.com"] {
+            assert!(check_email(bad).is_err(), "{}", bad);
+        }
+
+        assert!(check_telephone("+1 (801) 555-0100").is_ok());
+        for bad in &["12345", "1234567890123456", "801-555-0100 x1", "abcdefgh"] {
+            assert!(check_telephone(bad).is_err(), "{}", bad);
+        }
+
+        let gender = |v: Option<&str>| parse_gender(&v.map(str::to_string)).map(|g| g.map(|g| format!("{:?}", g)));
+        assert_eq!(gender(Some(" Male ")), Ok(Some("Male".to_string())));
+        assert_eq!(gender(Some("f")), Ok(Some("Female".to_string())));
+        assert_eq!(gender(Some("Not Specified")), Ok(None));
+        assert_eq!(gender(Some(" ")), Ok(None));
+        assert_eq!(gender(None), Ok(None));
+        assert!(gender(Some("x")).is_err());
+    }
+
+    #[test]
+    fn invalid_forms_do_not_map_to_requests() {
+        let request = individual().to_verify_identity_request("reference".to_string()).unwrap();
+        let person = request.datafields.person_info.unwrap();
+        assert_eq!((person.month_of_birth, person.day_of_birth, person.year_of_birth), (Some(2), Some(29), Some(2000)));
+
+        let bad_date = IndividualKycRequest { date_of_birth: "02/30/2000".to_string(), ..individual() };
+        assert!(bad_date.to_verify_identity_request("reference".to_string()).is_err());
+        let bad_gender = IndividualKycRequest { gender: Some("x".to_string()), ..individual() };
+        assert!(bad_gender.to_verify_identity_request("reference".to_string()).is_err());
+    }
+}
@@ -12,19 +12,33 @@ extern crate lazy_static;
 #[macro_use]
 extern crate rocket;
 
+mod api;
+mod applications;
+mod attestation;
+mod challenge;
 mod cmd_opt;
 mod config;
 mod secret_backend;
+mod store;
+mod watcher;
 mod consents;
+mod deposits;
+mod documents;
+mod kyc;
+mod nonces;
+mod orders;
+mod quotes;
+mod reconcile;
 mod responses;
+mod sandbox;
 
+use api::{ApiError, ApiResponse, ApiResult};
 use celes::Country;
 use cmd_opt::Opt;
 use config::Config;
-use ed25519_dalek::{Signature, PublicKey};
-use hmac::{Hmac, Mac};
+use hmac::Hmac;
 use lox::prelude::*;
-use rand::RngCore;
+use nonces::NonceStore;
 use rocket::{
     State
 };
@@ -35,18 +49,17 @@ use rocket_contrib::{
 };
 use secret_backend::SecretBackend;
 use serde::{Deserialize, Serialize};
-use sha2::{Sha256, Digest};
+use sha2::Sha256;
 use std::{
     collections::BTreeMap,
     error::Error,
     fs,
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     time::{SystemTime, UNIX_EPOCH}
 };
 use structopt::StructOpt;
-use subtle::ConstantTimeEq;
 use trulioo::TruliooRequest;
 
 const TOKEN_WEBSITE_SERVICE: &str = "token_website";
@@ -54,118 +67,192 @@ const TRULIOO_SERVICE: &str = "trulioo";
 
 type HmacSha256 = Hmac<Sha256>;
 
+#[derive(Serialize)]
+pub(crate) struct SimpleCountry {
+    alpha2: String,
+    long_name: String
+}
+
 #[get("/countries")]
-pub(crate) fn get_allowed_countries(countries: State<BTreeMap<String, Country>>) -> String {
-    #[derive(Serialize)]
-    struct SimpleCountry {
-        alpha2: String,
-        long_name: String
-    };
+pub(crate) fn get_allowed_countries(countries: State<BTreeMap<String, Country>>) -> ApiResult<Vec<SimpleCountry>> {
     let list = countries.inner().iter().map(|(_, c)| SimpleCountry { alpha2: c.alpha2.to_string(), long_name: c.long_name.to_string() }).collect::<Vec<SimpleCountry>>();
-    format!(r#"{{ "status": "success", "result": {} }}"#, serde_json::to_string(&list).unwrap())
+    ApiResponse::ok(list)
 }
 
 #[get("/consents/<country>")]
-pub(crate) fn get_consents(country: String, request: State<TruliooRequest>, countries: State<BTreeMap<String, Country>>) -> String {
+pub(crate) fn get_consents(country: String, request: State<TruliooRequest>, countries: State<BTreeMap<String, Country>>) -> ApiResult<Vec<trulioo::Consent>> {
     if !countries.inner().contains_key(&country) {
-        return format!(r#"{{ "status": "error", "message": {} }}"#, "Invalid country code");
+        return Err(ApiError::bad_request("Invalid country code"));
     }
 
-    let mut consents = Ok(Vec::new());
-    async_std::task::block_on(async {
-       consents = request.inner().get_detailed_consents(country).await
-    });
-    match consents {
-        Ok(c) => format!(r#"{{ "status": "success", "result": {} }}"#, serde_json::to_string(&c).unwrap()),
-        Err(e) => format!(r#"{{ "status": "error", "message": {} }}"#, e)
-    }
+    let consents = async_std::task::block_on(request.inner().get_detailed_consents(country));
+    consents.map(ApiResponse).map_err(ApiError::from)
 }
 
-#[get("/payment_address_challenge")]
-pub(crate) fn get_payment_address_challenge(challenge_signing_key: State<Vec<u8>>) -> String {
-    let mut rng = rand::rngs::OsRng{};
-    let mut result = generate_timestamp().unwrap().to_be_bytes().to_vec();
-    let mut challenge = vec![0u8; 32];
-    rng.fill_bytes(challenge.as_mut_slice());
-
-    let mut hmac = HmacSha256::new_varkey(&challenge_signing_key.inner().as_slice()).unwrap();
-    hmac.input(result.as_slice());
-    hmac.input(challenge.as_slice());
-    let hash = hmac.result().code();
-
-    result.extend_from_slice(challenge.as_slice());
-    result.extend_from_slice(hash.as_slice());
-
-    format!(r#"{{ "status": "success", "result": "{}" }}"#, base64_url::encode(result.as_slice()))
+#[get("/payment_address_challenge?<purpose>&<order_id>")]
+pub(crate) fn get_payment_address_challenge(purpose: Option<String>, order_id: Option<String>, challenge_keys: State<challenge::ChallengeKeys>) -> ApiResult<challenge::IssuedChallenge> {
+    let purpose = match purpose {
+        Some(p) => p.parse().map_err(ApiError::bad_request)?,
+        None => challenge::Purpose::default()
+    };
+    match (purpose, &order_id) {
+        (challenge::Purpose::AuthorizeOrder, None) => return Err(ApiError::bad_request("order_id is required to authorize an order")),
+        (challenge::Purpose::ProveAddress, Some(_)) => return Err(ApiError::bad_request("order_id is only used to authorize an order")),
+        _ => {}
+    };
+    challenge_keys.inner().issue(purpose, order_id).map(ApiResponse).map_err(ApiError::bad_request)
 }
 
 #[post("/payment_address_challenge", format = "application/json", data = "<challenge>")]
-pub(crate) fn verify_payment_address_challenge(challenge: Json<responses::PaymentAddressChallengeResponse>, challenge_signing_key: State<Vec<u8>>) -> String {
-    const TIMESTAMP: usize = 8;
-    const NONCE: usize = 32;
-    const EXPIRE: u64 = 3600;
+pub(crate) fn verify_payment_address_challenge(challenge: Json<responses::PaymentAddressChallengeResponse>, challenge_keys: State<challenge::ChallengeKeys>, nonces: State<Box<dyn NonceStore>>, attestor: State<attestation::Attestor>, store: State<store::Store>) -> ApiResult<attestation::AddressProof> {
     let response = challenge.into_inner();
 
-    let challenge = match base64_url::decode(&response.challenge) {
-        Err(why) => return format!(r#"{{ "status": "error", "message": {} }}"#, why.description()),
-        Ok(c) => c,
-    };
+    let challenge = base64_url::decode(&response.challenge).map_err(|why| ApiError::bad_request(why.description()))?;
 
-    let signature = match base64_url::decode(&response.signature) {
-        Err(why) => return format!(r#"{{ "status": "error", "message": {} }}"#, why.description()),
-        Ok(s) => s,
-    };
+    //Check if this is a current challenge from here
+    let decoded = challenge_keys.inner().verify(&challenge).map_err(ApiError::bad_request)?;
 
-    let timestamp = u64::from_be_bytes(*array_ref!(challenge, 0, TIMESTAMP));
-
-    if timestamp + EXPIRE < generate_timestamp().unwrap() {
-        return format!(r#"{{ "status": "error", "message": "Challenge has expired" }}"#);
+    if decoded.purpose != response.purpose || decoded.order_id != response.order_id {
+        return Err(ApiError::bad_request("Challenge was issued for a different purpose"));
     }
 
-    let mut hmac = HmacSha256::new_varkey(&challenge_signing_key.inner().as_slice()).unwrap();
-    hmac.input(&challenge[..(TIMESTAMP + NONCE)]);
-    let expected_tag = hmac.result().code();
+    let address = sovrin_signing::decode_payment_address(&response.address).map_err(ApiError::bad_request)?;
+    let verified = sovrin_signing::verify_challenge(&address.verkey, &challenge, &response.signature, response.scheme)
+        .map_err(ApiError::bad_request)?;
 
-    //Check if this is a challenge from here
-    if expected_tag.ct_eq(&challenge[(TIMESTAMP + NONCE)..]).unwrap_u8() != 1 {
-        return format!(r#"{{ "status": "error", "message": "Invalid challenge" }}"#);
+    if !verified {
+        return ApiResponse::ok(attestation::AddressProof { verified: false, address: address.address, format: address.format, attestation: None });
     }
 
-    if response.address.len() < 8 || &response.address[..8] != "pay:sov:" {
-        return format!(r#"{{ "status": "error", "message": "Unexpected address type" }}"#);
+    // Look everything up before the nonce is spent so a request naming an
+    // unknown application or order does not burn the challenge
+    let order = match decoded.purpose {
+        challenge::Purpose::ProveAddress => {
+            if let Some(ref id) = response.application_id {
+                let application = store.inner().get_application(id)
+                    .map_err(ApiError::internal)?
+                    .ok_or_else(|| ApiError::not_found("Unknown application"))?;
+                match application.payment_address {
+                    Some(ref a) if a != &address.address => return Err(ApiError::conflict("The application has a different payment address")),
+                    _ => {}
+                }
+            }
+            None
+        },
+        challenge::Purpose::AuthorizeOrder => {
+            let order_id = decoded.order_id.as_ref().ok_or_else(|| ApiError::bad_request("Challenge does not name an order"))?;
+            let order = store.inner().get_order(order_id)
+                .map_err(ApiError::internal)?
+                .ok_or_else(|| ApiError::not_found("Unknown order"))?;
+            let application = store.inner().get_application(&order.application_id)
+                .map_err(ApiError::internal)?
+                .ok_or_else(|| ApiError::not_found("Unknown application"))?;
+            if application.payment_address.as_ref() != Some(&address.address) {
+                return Err(ApiError::conflict("The order was not placed with this payment address"));
+            }
+            Some(order)
+        }
+    };
+
+    if !nonces.inner().redeem(&decoded.nonce, challenge_keys.inner().expires_at(decoded.issued_at)).map_err(ApiError::internal)? {
+        return Err(ApiError::conflict("Challenge has already been used"));
     }
 
-    let decodedkey = match bs58::decode(&response.address[8..]).with_check(None).into_vec() {
-        Err(_) => return format!(r#"{{ "status": "error", "message": "Invalid address" }}"#),
-        Ok(d) => d,
+    match order {
+        None => {
+            if let Some(ref id) = response.application_id {
+                let verified_at = generate_timestamp().map_err(ApiError::internal)?;
+                // Checked again in the update in case another address won a race
+                let application = store.inner().update_application(id, |a| {
+                    if a.payment_address.as_ref().map_or(true, |p| p == &address.address) {
+                        a.payment_address = Some(address.address.clone());
+                        a.address_verified_at = Some(verified_at);
+                    }
+                }).map_err(ApiError::internal)?;
+                if application.payment_address.as_ref() != Some(&address.address) {
+                    return Err(ApiError::conflict("The application has a different payment address"));
+                }
+            }
+        },
+        Some(ref order) => {
+            let authorized_at = generate_timestamp().map_err(ApiError::internal)?;
+            store.inner().update_order(&order.id, |o| o.authorized_at = Some(authorized_at))
+                .map_err(ApiError::internal)?;
+        }
+    }
+    // Only the order's own application was checked for an order proof
+    let application_id = match order {
+        Some(ref o) => Some(o.application_id.clone()),
+        None => response.application_id.clone()
     };
+    let attestation = attestor.inner().issue(&address.address, application_id, &challenge)
+        .map_err(ApiError::internal)?;
+    ApiResponse::ok(attestation::AddressProof { verified: true, address: address.address, format: address.format, attestation: Some(attestation) })
+}
 
-     let pubkey = match PublicKey::from_bytes(decodedkey.as_slice()) {
-        Err(_) => return format!(r#"{{ "status": "error", "message": "Address cannot be converted to a public key" }}"#),
-        Ok(p) => p,
-    };
+#[derive(Serialize)]
+pub(crate) struct Health {
+    /// "ok", or "degraded" while Trulioo calls are failing fast
+    status: &'static str,
+    /// Started with --test, nothing here is real
+    sandbox: bool,
+    trulioo: trulioo::CircuitStatus,
+    /// Absent when no nodes are watched for payments
+    watcher: Option<watcher::WatcherStatus>
+}
 
-    let sig = match Signature::from_bytes(signature.as_slice()) {
-        Err(_) => return format!(r#"{{ "status": "error", "message": "Invalid signature" }}"#),
-        Ok(s) => s,
+#[get("/health")]
+pub(crate) fn get_health(request: State<TruliooRequest>, sandbox: State<sandbox::Sandbox>, watcher_health: State<Option<watcher::WatcherHealth>>) -> ApiResult<Health> {
+    let trulioo = request.inner().circuit_status();
+    let watcher = watcher_health.inner().as_ref().map(|w| w.status());
+    let status = match (trulioo.state, &watcher) {
+        (trulioo::CircuitState::Closed, None) => "ok",
+        (trulioo::CircuitState::Closed, Some(w)) if w.last_error.is_none() => "ok",
+        _ => "degraded"
     };
+    ApiResponse::ok(Health { status, sandbox: sandbox.inner().0, trulioo, watcher })
+}
 
-    let mut sha = Sha256::new();
-    sha.input(format!("\x6DSovrin Signed Message:\nLength: {}\n", challenge.len()).as_bytes());
-    sha.input(challenge.as_slice());
-    let digest = sha.result();
+#[derive(Serialize)]
+pub(crate) struct PublishedChallengeKeys {
+    domain: String,
+    keys: Vec<challenge::PublishedKey>
+}
 
-    match pubkey.verify(digest.as_slice(), &sig) {
-        Err(_) => format!(r#"{{ "status": "success", "result": false }}"#),
-        Ok(_) => format!(r#"{{ "status": "success", "result": true }} "#)
+/// Lets anyone check a proven address without asking us. Decode the
+/// challenge, find its key id here, verify the ed25519 signature over the
+/// challenge bytes before it, then verify the wallet's signature over the
+/// whole challenge.
+#[get("/.well-known/challenge-keys")]
+pub(crate) fn get_challenge_keys(challenge_keys: State<challenge::ChallengeKeys>) -> ApiResult<PublishedChallengeKeys> {
+    let keys = challenge_keys.inner().public_keys();
+    if keys.is_empty() {
+        return Err(ApiError::not_found("Challenges are not publicly verifiable"));
     }
+    ApiResponse::ok(PublishedChallengeKeys { domain: challenge_keys.inner().domain().to_string(), keys })
 }
 
 fn main() {
     let opt = Opt::from_args();
     let config = get_config(&opt);
 
-    let request = get_trulioo_request(&config);
+    match opt.cmd {
+        Some(cmd_opt::Command::ImportStatement { ref file, format }) => {
+            import_statement(&open_store(&config), file, format);
+            return;
+        },
+        Some(cmd_opt::Command::RotateChallengeKey { grace_period }) => {
+            rotate_challenge_key(&opt, config, grace_period);
+            return;
+        },
+        None => {}
+    }
+
+    let (request, _mock_trulioo) = if config.sandbox {
+        sandbox::trulioo_request(&config)
+    } else {
+        (get_trulioo_request(&config), None)
+    };
     let mut countries = BTreeMap::new();
 
     async_std::task::block_on(async {
@@ -183,32 +270,169 @@ fn main() {
     if !home.exists() {
         fs::create_dir_all(home.clone()).unwrap();
     }
+    let document_store = match documents::DocumentStore::new(home.join(if config.sandbox { "sandbox-documents" } else { "documents" })) {
+        Err(why) => panic!("Unable to open the document store: {}", why),
+        Ok(d) => d
+    };
+    let deposit_addresses = if config.sandbox {
+        sandbox::deposit_addresses(&config)
+    } else {
+        deposits::DepositAddresses::new(&config.deposits.clone().unwrap_or_default())
+    };
+    let deposit_addresses = match deposit_addresses {
+        Err(why) => panic!("Unable to load deposit keys: {}", why),
+        Ok(d) => d
+    };
+    let store = open_store(&config);
+    let watcher = if config.sandbox { &config.sandbox_watcher } else { &config.watcher };
+    let watcher_health = watcher.as_ref().map(|w| {
+        let health = watcher::WatcherHealth::default();
+        watcher::spawn(w.clone(), store.clone(), health.clone());
+        health
+    });
+    let challenges = config.challenges.clone().unwrap_or_default();
+    let domain = challenges.domain.clone().unwrap_or_else(|| format!("localhost:{}", config.port));
+    let challenge_keys = match challenge::ChallengeKeys::new(&config.keys, domain, &challenges) {
+        Err(why) => panic!("Unable to load challenge keys: {}", why),
+        Ok(k) => k
+    };
+    if config.keys.attestation_signing_key.is_none() {
+        eprintln!("No attestation_signing_key is configured, attestations issued now stop verifying after a restart");
+    }
+    let attestor = match attestation::Attestor::new(config.keys.attestation_signing_key.as_ref()) {
+        Err(why) => panic!("Unable to load the attestation key: {}", why),
+        Ok(a) => a
+    };
+    let quoter = match quotes::Quoter::new(&config.quotes.clone().unwrap_or_default(), config.keys.quote_signing_key.as_ref()) {
+        Err(why) => panic!("Unable to load the quote key: {}", why),
+        Ok(q) => q
+    };
+    let nonce_store: Box<dyn NonceStore> = if challenges.persist_nonces {
+        Box::new(store.clone())
+    } else {
+        Box::new(nonces::MemoryNonceStore::default())
+    };
     home.push("config");
 
     if !home.exists() {
-        let mut file = match fs::File::create(&home) {
-            Err(why) => panic!("Couldn't create {:?}: {}", home, why.description()),
-            Ok(file) => file
-        };
-        println!("config = {:?}", config);
-        let recipe_toml = toml::Value::try_from(&config).unwrap();
-        let contents = toml::to_string(&recipe_toml).unwrap();
-        println!("contents = {}", contents);
-        if let Err(why) = file.write_all(contents.as_bytes()) {
-            panic!("Unable to write to {:?}: {}", home, why.description());
-        }
+        save_config(&home, &config);
     }
 
-    rocket::ignite()
+    let sandbox_mode = sandbox::Sandbox(config.sandbox);
+    let mut rocket = rocket::ignite()
         .attach(SpaceHelmet::default())
         .manage(countries)
-        .manage(base64_url::decode(&config.keys.challenge_signing_key).unwrap())
+        .manage(challenge_keys)
+        .manage(nonce_store)
+        .manage(attestor)
         .manage(request)
+        .manage(store)
+        .manage(config.bank.clone())
+        .manage(config.pricing.clone().unwrap_or_default())
+        .manage(deposit_addresses)
+        .manage(quoter)
+        .manage(document_store)
+        .manage(sandbox_mode)
+        .manage(watcher_health)
+        .register(catchers![api::bad_request,
+                            api::not_found,
+                            api::unprocessable_entity,
+                            api::internal_error])
         .mount("/", StaticFiles::from(concat!(env!("CARGO_MANIFEST_DIR"), "/public")))
+        .mount("/", routes![get_challenge_keys])
         .mount("/api/v1", routes![get_allowed_countries,
                                       get_consents,
+                                      get_health,
                                       get_payment_address_challenge,
-                                      verify_payment_address_challenge]).launch();
+                                      verify_payment_address_challenge,
+                                      applications::create_application,
+                                      applications::get_application,
+                                      applications::accept_consents,
+                                      attestation::verify_attestation,
+                                      quotes::create_quote,
+                                      orders::create_order,
+                                      orders::get_order,
+                                      kyc::verify_individual,
+                                      kyc::verify_business,
+                                      kyc::search_business,
+                                      documents::verify_document]);
+    if sandbox_mode.0 {
+        rocket = rocket.mount("/api/v1", routes![sandbox::get_test_entities]);
+    }
+    rocket.launch();
+}
+
+fn open_store(config: &Config) -> store::Store {
+    let mut path = config.database.clone().unwrap_or_else(|| {
+        let mut path = PathBuf::new();
+        path.push(env!("HOME"));
+        path.push(".token-website");
+        path.push("db");
+        path
+    });
+    // A configured database gets its sandbox beside it
+    if config.sandbox {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        path.set_file_name(format!("sandbox-{}", name));
+    }
+    match store::Store::open(&path) {
+        Err(why) => panic!("Unable to open the application database {:?}: {}", path, why),
+        Ok(s) if config.sandbox => s.sandboxed(),
+        Ok(s) => s
+    }
+}
+
+fn import_statement(store: &store::Store, file: &Path, format: Option<reconcile::StatementFormat>) {
+    let credits = match reconcile::read_statement(file, format) {
+        Err(why) => panic!("Unable to read the statement: {}", why),
+        Ok(c) => c
+    };
+    let result = match reconcile::reconcile(store, &credits) {
+        Err(why) => panic!("Reconciliation failed: {}", why),
+        Ok(r) => r
+    };
+    println!("Matched ({}):", result.matched.len());
+    for m in &result.matched {
+        println!("  {}", m);
+    }
+    println!("Needs review ({}):", result.review.len());
+    for r in &result.review {
+        println!("  {}", r);
+    }
+    println!("Unmatched ({}):", result.unmatched.len());
+    for u in &result.unmatched {
+        println!("  {}", u);
+    }
+    println!("Already imported: {}", result.duplicates);
+}
+
+/// Generate a new challenge signing key and write it to the config file in
+/// use. The old key keeps verifying challenges for `grace_period` seconds.
+fn rotate_challenge_key(opt: &Opt, mut config: Config, grace_period: u64) {
+    if let Err(why) = config.keys.rotate_challenge_key(grace_period) {
+        panic!("Unable to rotate the challenge signing key: {}", why);
+    }
+    let path = opt.config.clone().unwrap_or_else(|| {
+        let mut path = PathBuf::new();
+        path.push(env!("HOME"));
+        path.push(".token-website");
+        path.push("config");
+        path
+    });
+    save_config(&path, &config);
+    println!("Rotated the challenge signing key, {} retired key(s) in {:?}", config.keys.retired_challenge_keys.len(), path);
+}
+
+fn save_config(path: &Path, config: &Config) {
+    let mut file = match fs::File::create(path) {
+        Err(why) => panic!("Couldn't create {:?}: {}", path, why.description()),
+        Ok(file) => file
+    };
+    let recipe_toml = toml::Value::try_from(config).unwrap();
+    let contents = toml::to_string(&recipe_toml).unwrap();
+    if let Err(why) = file.write_all(contents.as_bytes()) {
+        panic!("Unable to write to {:?}: {}", path, why.description());
+    }
 }
 
 fn get_trulioo_request(config: &Config) -> TruliooRequest {
@@ -227,7 +451,25 @@ fn get_trulioo_request(config: &Config) -> TruliooRequest {
         key = prompt_for_value(trulioo::API_KEY_HEADER);
     }
 
-    TruliooRequest { key, url }
+    let client = config.trulioo_client.clone().unwrap_or_default();
+    let mut builder = TruliooRequest::builder()
+        .key(key)
+        .url(url)
+        .policy(trulioo::ClientPolicy::from(&client))
+        .user_agent(client.user_agent.unwrap_or_else(|| format!("token-website/{}", env!("CARGO_PKG_VERSION"))));
+    if let Some(proxy) = client.proxy {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(ca_bundle) = client.ca_bundle {
+        builder = builder.ca_bundle(ca_bundle);
+    }
+    if let Some(max) = client.max_connections {
+        builder = builder.max_connections(max);
+    }
+    match builder.build() {
+        Err(why) => panic!("Unable to create the Trulioo client: {}", why),
+        Ok(r) => r
+    }
 }
 
 fn get_trulioo_secret(key_name: &str, secret_backend: Option<SecretBackend>) -> Vec<u8> {
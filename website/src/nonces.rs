@@ -0,0 +1,54 @@
+use crate::store::Store;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+
+/// Remembers which payment address challenges have been redeemed so each
+/// one can only be used once
+pub(crate) trait NonceStore: Send + Sync {
+    /// Mark `nonce` as used until `expires_at`, false if it already was
+    fn redeem(&self, nonce: &[u8], expires_at: u64) -> Result<bool, String>;
+}
+
+/// Forgets everything on restart, which is fine for a single instance
+/// since challenges only live for a short while
+#[derive(Default)]
+pub(crate) struct MemoryNonceStore {
+    used: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl NonceStore for MemoryNonceStore {
+    fn redeem(&self, nonce: &[u8], expires_at: u64) -> Result<bool, String> {
+        let now = crate::generate_timestamp()?;
+        let mut used = self.used.lock().map_err(|e| e.to_string())?;
+        used.retain(|_, e| *e >= now);
+        if used.contains_key(nonce) {
+            return Ok(false);
+        }
+        used.insert(nonce.to_vec(), expires_at);
+        Ok(true)
+    }
+}
+
+/// Kept in the application database so a restart cannot be used to replay
+/// a challenge
+impl NonceStore for Store {
+    fn redeem(&self, nonce: &[u8], expires_at: u64) -> Result<bool, String> {
+        self.redeem_challenge_nonce(nonce, expires_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonce_is_single_use() {
+        let store = MemoryNonceStore::default();
+        let expires_at = crate::generate_timestamp().unwrap() + 60;
+        assert!(store.redeem(b"nonce", expires_at).unwrap());
+        assert!(!store.redeem(b"nonce", expires_at).unwrap());
+        assert!(store.redeem(b"other", expires_at).unwrap());
+    }
+}
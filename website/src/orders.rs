@@ -0,0 +1,298 @@
+use crate::{
+    api::{ApiError, ApiResponse, ApiResult},
+    attestation::Attestor,
+    config::{BankAccount, Pricing},
+    deposits::{DepositAddress, DepositAddresses},
+    kyc::{generate_reference_id, KycStatus},
+    quotes::{CryptoCurrency, Quoter},
+    reconcile::generate_payment_reference,
+    store::Store,
+};
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+
+/// Decimal places of the fiat currency the tokens are priced in
+pub(crate) const FIAT_DECIMALS: u32 = 2;
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) enum PaymentMethod {
+    #[serde(rename = "fiat")]
+    Fiat,
+    #[serde(rename = "btc", alias = "bitcoin")]
+    Bitcoin,
+    #[serde(rename = "ether", alias = "eth")]
+    Ether,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OrderState {
+    Pending,
+    #[serde(rename = "partially_paid")]
+    PartiallyPaid,
+    Paid,
+    Overpaid,
+    /// Paid with a mismatch that needs a person to look at it
+    Review,
+}
+
+impl OrderState {
+    /// Whether the order is still waiting for (more) funds
+    pub fn is_open(self) -> bool {
+        match self {
+            OrderState::Pending | OrderState::PartiallyPaid => true,
+            OrderState::Paid | OrderState::Overpaid | OrderState::Review => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Order {
+    pub id: String,
+    pub application_id: String,
+    pub created: u64,
+    pub tokens: u64,
+    pub payment_method: PaymentMethod,
+    /// In the minor unit of `currency`
+    pub amount_due: u64,
+    pub currency: String,
+    pub state: OrderState,
+    /// Where crypto payments for this order are sent
+    pub deposit: Option<DepositAddress>,
+    /// Received with enough confirmations, in the minor unit of `currency`
+    #[serde(default)]
+    pub amount_received: u64,
+    /// Received including unconfirmed payments
+    #[serde(default)]
+    pub amount_seen: u64,
+    /// Hashes of the ether transfers counted in `amount_received`
+    #[serde(default)]
+    pub transactions: Vec<String>,
+    /// The reference fiat payers put on their wire transfer
+    #[serde(default)]
+    pub payment_reference: Option<String>,
+    /// Why the order needs review
+    #[serde(default)]
+    pub notes: Vec<String>,
+    /// When the payment address holder signed an authorize-order challenge
+    #[serde(default)]
+    pub authorized_at: Option<u64>,
+    /// Placed in sandbox mode, never fulfilled
+    #[serde(default)]
+    pub test: bool,
+}
+
+impl Order {
+    /// Decimal places of `amount_due`
+    pub fn decimals(&self) -> u32 {
+        CryptoCurrency::from_payment_method(self.payment_method)
+            .map(|c| c.decimals())
+            .unwrap_or(FIAT_DECIMALS)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct PaymentInstructions {
+    pub payment_method: PaymentMethod,
+    /// Decimal amount e.g. "12.50"
+    pub amount_due: String,
+    pub currency: String,
+    /// The address to send crypto payments to
+    pub pay_to: Option<String>,
+    /// The account to wire fiat payments to
+    pub bank: Option<BankAccount>,
+    /// Include this with the payment so it can be matched to the order
+    pub reference: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct OrderCreated {
+    pub order_id: String,
+    pub state: OrderState,
+    pub instructions: PaymentInstructions,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct OrderRequest {
+    pub application_id: String,
+    pub tokens: u64,
+    pub payment_method: PaymentMethod,
+    /// Required for crypto payments, see `quotes::create_quote`
+    pub quote: Option<String>,
+    /// Sets the payment address first, see `Attestor::present`
+    #[serde(default)]
+    pub attestation: Option<String>,
+}
+
+impl Pricing {
+    /// The fiat price of `tokens` in minor units
+    pub fn price(&self, tokens: u64) -> Result<u64, String> {
+        if tokens < self.minimum_tokens || tokens > self.maximum_tokens {
+            return Err(format!("Token quantity must be between {} and {}", self.minimum_tokens, self.maximum_tokens));
+        }
+        tokens
+            .checked_mul(self.token_price)
+            .ok_or_else(|| "Token quantity is too large".to_string())
+    }
+}
+
+/// Parse a decimal string e.g. "12.5" into minor units
+pub(crate) fn parse_amount(value: &str, decimals: u32) -> Result<u64, String> {
+    let err = || format!("Invalid amount {}", value);
+    let mut parts = value.splitn(2, '.');
+    let whole = parts.next().unwrap_or("");
+    let fraction = parts.next();
+    if fraction == Some("") {
+        return Err(err());
+    }
+    let fraction = fraction.unwrap_or("");
+    if whole.is_empty() || fraction.len() > decimals as usize
+        || !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+        return Err(err());
+    }
+    let fraction = format!("{:0<width$}", fraction, width = decimals as usize);
+    let whole = whole.parse::<u64>().map_err(|_| err())?;
+    let fraction = if fraction.is_empty() { 0 } else { fraction.parse::<u64>().map_err(|_| err())? };
+    10u64
+        .checked_pow(decimals)
+        .and_then(|scale| whole.checked_mul(scale))
+        .and_then(|w| w.checked_add(fraction))
+        .ok_or_else(err)
+}
+
+/// Render an amount held in minor units as a decimal string
+pub(crate) fn format_amount(amount: u64, decimals: u32) -> String {
+    if decimals == 0 {
+        return amount.to_string();
+    }
+    let scale = 10u64.pow(decimals);
+    format!("{}.{:0width$}", amount / scale, amount % scale, width = decimals as usize)
+}
+
+#[post("/orders", format = "application/json", data = "<order>")]
+pub(crate) fn create_order(order: Json<OrderRequest>, pricing: State<Pricing>, bank: State<Option<BankAccount>>, quoter: State<Quoter>, addresses: State<DepositAddresses>, attestor: State<Attestor>, store: State<Store>) -> ApiResult<OrderCreated> {
+    let order = order.into_inner();
+    let pricing = pricing.inner();
+
+    let mut application = store.inner().get_application(&order.application_id)
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found("Unknown application"))?;
+    if let Some(ref token) = order.attestation {
+        application = attestor.inner().present(token, &application, store.inner())?;
+    }
+    if application.payment_address.is_none() {
+        return Err(ApiError::conflict("A payment address has not been proven"));
+    }
+    match application.individual {
+        Some(ref r) if r.status == KycStatus::Match => {},
+        _ => return Err(ApiError::conflict("KYC has not been completed"))
+    };
+
+    let (amount_due, currency) = match CryptoCurrency::from_payment_method(order.payment_method) {
+        None if bank.inner().is_none() => return Err(ApiError::service_unavailable("Fiat payments are not available")),
+        None => (pricing.price(order.tokens).map_err(ApiError::bad_request)?, pricing.currency.clone()),
+        Some(crypto) => {
+            if !addresses.inner().supports(crypto) {
+                return Err(ApiError::service_unavailable(format!("{} payments are not available", crypto.code())));
+            }
+            let quote = match order.quote {
+                None => return Err(ApiError::bad_request("A quote is required for crypto payments")),
+                Some(ref q) => quoter.inner().verify(q).map_err(ApiError::bad_request)?
+            };
+            if quote.currency != crypto || quote.tokens != order.tokens {
+                return Err(ApiError::bad_request("The quote does not match the order"));
+            }
+            // One quote places one order, it cannot hold a rate for more
+            if !store.inner().redeem_quote(&quote.id, quote.expires_at).map_err(ApiError::internal)? {
+                return Err(ApiError::conflict("The quote has already been used"));
+            }
+            (quote.amount_due, crypto.code().to_string())
+        }
+    };
+
+    let id = generate_reference_id();
+    let deposit = match CryptoCurrency::from_payment_method(order.payment_method) {
+        None => None,
+        Some(crypto) => Some(store.inner().allocate_deposit_address(crypto, &id, addresses.inner()).map_err(ApiError::internal)?)
+    };
+
+    let order = Order {
+        id,
+        application_id: application.id,
+        created: crate::generate_timestamp().map_err(ApiError::internal)?,
+        tokens: order.tokens,
+        payment_method: order.payment_method,
+        amount_due,
+        currency,
+        state: OrderState::Pending,
+        deposit,
+        amount_received: 0,
+        amount_seen: 0,
+        transactions: Vec::new(),
+        payment_reference: match order.payment_method {
+            PaymentMethod::Fiat => Some(generate_payment_reference()),
+            _ => None
+        },
+        notes: Vec::new(),
+        authorized_at: None,
+        test: store.inner().sandbox(),
+    };
+    store.inner().create_order(&order).map_err(ApiError::internal)?;
+
+    ApiResponse::ok(OrderCreated {
+        order_id: order.id.clone(),
+        state: order.state,
+        instructions: PaymentInstructions {
+            payment_method: order.payment_method,
+            amount_due: format_amount(order.amount_due, order.decimals()),
+            currency: order.currency,
+            pay_to: order.deposit.map(|d| d.address),
+            bank: order.payment_reference.as_ref().and_then(|_| bank.inner().clone()),
+            reference: order.payment_reference.unwrap_or(order.id),
+        },
+    })
+}
+
+#[get("/orders/<id>")]
+pub(crate) fn get_order(id: String, store: State<Store>) -> ApiResult<Order> {
+    store.inner().get_order(&id)
+        .map_err(ApiError::internal)?
+        .map(ApiResponse)
+        .ok_or_else(|| ApiError::not_found("Unknown order"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_round_trip() {
+        assert_eq!(parse_amount("12.5", 2), Ok(1250));
+        assert_eq!(parse_amount("12.05", 2), Ok(1205));
+        assert_eq!(parse_amount("12", 2), Ok(1200));
+        assert_eq!(parse_amount("0.00000001", 8), Ok(1));
+        assert!(parse_amount("12.345", 2).is_err());
+        assert!(parse_amount("12.", 2).is_err());
+        assert!(parse_amount(".5", 2).is_err());
+        assert!(parse_amount("-1", 2).is_err());
+        assert!(parse_amount("1e5", 2).is_err());
+        assert!(parse_amount("", 2).is_err());
+
+        assert_eq!(parse_amount("184467440737095516.15", 2), Ok(u64::max_value()));
+        assert!(parse_amount("184467440737095516.16", 2).is_err());
+        assert!(parse_amount("18446744073709551616", 0).is_err());
+        assert!(parse_amount("1", 20).is_err());
+
+        assert_eq!(parse_amount("42", 0), Ok(42));
+        assert!(parse_amount("42.0", 0).is_err());
+        assert_eq!(format_amount(42, 0), "42");
+
+        assert_eq!(format_amount(1250, 2), "12.50");
+        assert_eq!(format_amount(5, 8), "0.00000005");
+        assert_eq!(format_amount(u64::max_value(), 2), "184467440737095516.15");
+        for &(amount, decimals) in &[(0, 2), (1, 9), (123_456_789, 8), (u64::max_value(), 9)] {
+            assert_eq!(parse_amount(&format_amount(amount, decimals), decimals), Ok(amount));
+        }
+    }
+}
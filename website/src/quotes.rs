@@ -0,0 +1,265 @@
+use crate::{
+    api::{ApiError, ApiResponse, ApiResult},
+    config::{Pricing, Quotes},
+    orders::PaymentMethod,
+    HmacSha256,
+};
+use hmac::Mac;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+};
+use zeroize::Zeroize;
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) enum CryptoCurrency {
+    #[serde(rename = "BTC")]
+    Bitcoin,
+    #[serde(rename = "ETH")]
+    Ether,
+}
+
+impl CryptoCurrency {
+    pub fn from_payment_method(method: PaymentMethod) -> Option<Self> {
+        match method {
+            PaymentMethod::Bitcoin => Some(CryptoCurrency::Bitcoin),
+            PaymentMethod::Ether => Some(CryptoCurrency::Ether),
+            PaymentMethod::Fiat => None,
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            CryptoCurrency::Bitcoin => "BTC",
+            CryptoCurrency::Ether => "ETH",
+        }
+    }
+
+    /// Amounts are held in satoshis for bitcoin and gwei for ether
+    pub fn decimals(self) -> u32 {
+        match self {
+            CryptoCurrency::Bitcoin => 8,
+            CryptoCurrency::Ether => 9,
+        }
+    }
+}
+
+/// Somewhere to find exchange rates. Rates are the price of one whole coin
+/// in the minor unit of the pricing currency.
+pub(crate) trait RateSource: Send + Sync {
+    fn rate(&self, currency: CryptoCurrency) -> Result<u64, String>;
+}
+
+/// Rates fixed in the config
+pub(crate) struct StaticRateSource {
+    rates: BTreeMap<String, u64>,
+}
+
+impl RateSource for StaticRateSource {
+    fn rate(&self, currency: CryptoCurrency) -> Result<u64, String> {
+        lookup_rate(&self.rates, currency)
+    }
+}
+
+/// Rates read from a toml file on each request so an operator can update
+/// them without a restart
+pub(crate) struct FileRateSource {
+    path: PathBuf,
+}
+
+impl RateSource for FileRateSource {
+    fn rate(&self, currency: CryptoCurrency) -> Result<u64, String> {
+        let contents = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Unable to read {:?}: {}", self.path, e))?;
+        let rates: BTreeMap<String, u64> = toml::from_str(&contents)
+            .map_err(|e| format!("Unable to parse {:?}: {}", self.path, e))?;
+        lookup_rate(&rates, currency)
+    }
+}
+
+fn lookup_rate(rates: &BTreeMap<String, u64>, currency: CryptoCurrency) -> Result<u64, String> {
+    match rates.get(currency.code()) {
+        Some(&r) if r > 0 => Ok(r),
+        _ => Err(format!("No exchange rate available for {}", currency.code())),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Quote {
+    /// Redeemed by the order placed with the quote
+    pub id: String,
+    pub tokens: u64,
+    pub currency: CryptoCurrency,
+    /// In satoshis or gwei, see `CryptoCurrency::decimals`
+    pub amount_due: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct SignedQuote {
+    pub quote: Quote,
+    /// Decimal `amount_due` e.g. "0.00125000"
+    pub amount: String,
+    /// Hand this back when creating the order
+    pub token: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct QuoteRequest {
+    pub tokens: u64,
+    pub payment_method: PaymentMethod,
+}
+
+/// Issues and checks quotes that lock an exchange rate for a while
+pub(crate) struct Quoter {
+    key: Vec<u8>,
+    lifetime: u64,
+    source: Box<dyn RateSource>,
+}
+
+impl Quoter {
+    pub fn new(config: &Quotes, key: Option<&String>) -> Result<Self, String> {
+        let source: Box<dyn RateSource> = match config.rates_file {
+            Some(ref path) => Box::new(FileRateSource { path: path.clone() }),
+            None => Box::new(StaticRateSource { rates: config.rates.clone().unwrap_or_default() }),
+        };
+        Self::with_source(config, key, source)
+    }
+
+    pub fn with_source(config: &Quotes, key: Option<&String>, source: Box<dyn RateSource>) -> Result<Self, String> {
+        let key = match key {
+            Some(k) => base64_url::decode(k).map_err(|e| format!("Invalid quote signing key: {}", e))?,
+            None => base64_url::decode(&crate::config::Keys::generate_key()).unwrap(),
+        };
+        if key.is_empty() {
+            return Err("Invalid quote signing key: empty".to_string());
+        }
+        Ok(Self { key, lifetime: config.lifetime, source })
+    }
+
+    pub fn issue(&self, tokens: u64, currency: CryptoCurrency, pricing: &Pricing) -> Result<SignedQuote, String> {
+        self.issue_at(tokens, currency, pricing, crate::generate_timestamp()?)
+    }
+
+    fn issue_at(&self, tokens: u64, currency: CryptoCurrency, pricing: &Pricing, now: u64) -> Result<SignedQuote, String> {
+        let fiat = pricing.price(tokens)?;
+        let rate = self.source.rate(currency)?;
+        let scale = 10u128.pow(currency.decimals());
+        // Round up so the buyer never underpays
+        let amount_due = (fiat as u128 * scale + rate as u128 - 1) / rate as u128;
+        if amount_due > u64::max_value() as u128 {
+            return Err("Token quantity is too large".to_string());
+        }
+        let quote = Quote {
+            id: crate::kyc::generate_reference_id(),
+            tokens,
+            currency,
+            amount_due: amount_due as u64,
+            expires_at: now.saturating_add(self.lifetime),
+        };
+        let payload = serde_json::to_vec(&quote).map_err(|e| e.to_string())?;
+        let token = format!("{}.{}", base64_url::encode(&payload), base64_url::encode(&self.tag(&payload)));
+        Ok(SignedQuote {
+            amount: crate::orders::format_amount(quote.amount_due, currency.decimals()),
+            quote,
+            token,
+        })
+    }
+
+    /// Check the quote came from here and has not expired
+    pub fn verify(&self, token: &str) -> Result<Quote, String> {
+        let mut parts = token.splitn(2, '.');
+        let (payload, tag) = match (parts.next(), parts.next()) {
+            (Some(p), Some(t)) => (p, t),
+            _ => return Err("Invalid quote".to_string()),
+        };
+        let payload = base64_url::decode(payload).map_err(|_| "Invalid quote".to_string())?;
+        let tag = base64_url::decode(tag).map_err(|_| "Invalid quote".to_string())?;
+
+        let mut hmac = HmacSha256::new_varkey(&self.key).unwrap();
+        hmac.input(&payload);
+        if hmac.verify(&tag).is_err() {
+            return Err("Invalid quote".to_string());
+        }
+
+        let quote: Quote = serde_json::from_slice(&payload).map_err(|_| "Invalid quote".to_string())?;
+        if quote.expires_at < crate::generate_timestamp()? {
+            return Err("Quote has expired".to_string());
+        }
+        Ok(quote)
+    }
+
+    fn tag(&self, payload: &[u8]) -> Vec<u8> {
+        let mut hmac = HmacSha256::new_varkey(&self.key).unwrap();
+        hmac.input(payload);
+        hmac.result().code().to_vec()
+    }
+}
+
+impl Drop for Quoter {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+#[post("/quotes", format = "application/json", data = "<request>")]
+pub(crate) fn create_quote(request: Json<QuoteRequest>, quoter: State<Quoter>, pricing: State<Pricing>) -> ApiResult<SignedQuote> {
+    let request = request.into_inner();
+    let currency = CryptoCurrency::from_payment_method(request.payment_method)
+        .ok_or_else(|| ApiError::bad_request("Quotes are only issued for crypto payments"))?;
+    quoter.inner().issue(request.tokens, currency, pricing.inner())
+        .map(ApiResponse)
+        .map_err(ApiError::bad_request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_rate(rate: u64) -> Quoter {
+        let mut rates = BTreeMap::new();
+        rates.insert("BTC".to_string(), rate);
+        Quoter::with_source(&Quotes::default(), None, Box::new(StaticRateSource { rates })).unwrap()
+    }
+
+    #[test]
+    fn quotes_round_up() {
+        let pricing = Pricing { token_price: 2, ..Pricing::default() };
+        // $100 of tokens at $30,000 per bitcoin is 333,333.33 satoshis
+        let quote = with_rate(3_000_000).issue(5_000, CryptoCurrency::Bitcoin, &pricing).unwrap();
+        assert_eq!(quote.quote.amount_due, 333_334);
+        assert_eq!(quote.amount, "0.00333334");
+        // Exact conversions are not bumped
+        assert_eq!(with_rate(1_000_000).issue(5_000, CryptoCurrency::Bitcoin, &pricing).unwrap().quote.amount_due, 1_000_000);
+
+        assert!(with_rate(1).issue(1_000_000_000, CryptoCurrency::Bitcoin, &Pricing { token_price: u64::max_value() / 1_000_000_000, ..pricing.clone() }).is_err());
+        assert!(with_rate(3_000_000).issue(5_000, CryptoCurrency::Ether, &pricing).is_err());
+        assert!(with_rate(3_000_000).issue(0, CryptoCurrency::Bitcoin, &pricing).is_err());
+    }
+
+    #[test]
+    fn quotes_are_signed_and_expire() {
+        let pricing = Pricing::default();
+        let quoter = with_rate(3_000_000);
+        let quote = quoter.issue(5_000, CryptoCurrency::Bitcoin, &pricing).unwrap();
+        assert_eq!(quoter.verify(&quote.token).unwrap(), quote.quote);
+
+        let (payload, tag) = quote.token.split_at(quote.token.find('.').unwrap());
+        let mut tampered: Quote = serde_json::from_slice(&base64_url::decode(payload).unwrap()).unwrap();
+        tampered.amount_due = 1;
+        let forged = format!("{}{}", base64_url::encode(&serde_json::to_vec(&tampered).unwrap()), tag);
+        assert_eq!(quoter.verify(&forged).unwrap_err(), "Invalid quote");
+        assert!(quoter.verify(&payload).is_err());
+        assert!(with_rate(3_000_000).verify(&quote.token).is_err(), "a different key");
+
+        let now = crate::generate_timestamp().unwrap();
+        let expired = quoter.issue_at(5_000, CryptoCurrency::Bitcoin, &pricing, now - Quotes::default().lifetime - 1).unwrap();
+        assert_eq!(quoter.verify(&expired.token).unwrap_err(), "Quote has expired");
+
+        assert!(Quoter::new(&Quotes::default(), Some(&"not base64!".to_string())).is_err());
+    }
+}
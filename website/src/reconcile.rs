@@ -0,0 +1,423 @@
+use crate::{
+    orders::{parse_amount, OrderState, PaymentMethod, FIAT_DECIMALS},
+    store::Store,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    str::FromStr,
+};
+
+const REFERENCE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const REFERENCE_BODY_LENGTH: usize = 12;
+
+/// Generate an ISO 11649 structured creditor reference e.g. RF18ABCD2345EFGH
+pub(crate) fn generate_payment_reference() -> String {
+    let mut rng = rand::rngs::OsRng{};
+    let body = (0..REFERENCE_BODY_LENGTH)
+        .map(|_| REFERENCE_ALPHABET[rng.gen_range(0, REFERENCE_ALPHABET.len())] as char)
+        .collect::<String>();
+    format!("RF{:02}{}", 98 - mod97(&format!("{}RF00", body)), body)
+}
+
+/// Pull the first valid creditor reference out of free text. Banks often
+/// add spaces or change the case so both are ignored.
+pub(crate) fn find_payment_reference(text: &str) -> Option<String> {
+    let compact = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    let mut start = 0;
+    while let Some(offset) = compact[start..].find("RF") {
+        let begin = start + offset;
+        let candidate = compact[begin..]
+            .chars()
+            .take(4 + REFERENCE_BODY_LENGTH)
+            .collect::<String>();
+        if candidate.len() == 4 + REFERENCE_BODY_LENGTH
+            && candidate.chars().all(|c| c.is_ascii_alphanumeric())
+            && is_valid_reference(&candidate)
+        {
+            return Some(candidate);
+        }
+        start = begin + 2;
+    }
+    None
+}
+
+fn is_valid_reference(reference: &str) -> bool {
+    let (head, body) = reference.split_at(4);
+    mod97(&format!("{}{}", body, head)) == 1
+}
+
+/// ISO 7064 mod 97-10 with letters mapped to 10..35
+fn mod97(text: &str) -> u32 {
+    text.chars().fold(0u32, |acc, c| match c.to_digit(36) {
+        Some(d) if d >= 10 => (acc * 100 + d) % 97,
+        Some(d) => (acc * 10 + d) % 97,
+        None => acc,
+    })
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) enum StatementFormat {
+    Csv,
+    Camt053,
+}
+
+impl FromStr for StatementFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(StatementFormat::Csv),
+            "camt053" | "camt.053" | "xml" => Ok(StatementFormat::Camt053),
+            _ => Err(format!("Unknown statement format: {}", s)),
+        }
+    }
+}
+
+/// An incoming payment on a bank statement
+#[derive(Clone, Debug)]
+pub(crate) struct Credit {
+    pub date: String,
+    /// In the minor unit of `currency`
+    pub amount: u64,
+    pub currency: String,
+    /// Remittance information, where the payer puts our reference
+    pub details: String,
+    /// The bank's own id for the entry, when the statement has one
+    pub entry_reference: Option<String>,
+}
+
+impl Credit {
+    fn summary(&self) -> String {
+        format!("{} {} {} \"{}\"", self.date, crate::orders::format_amount(self.amount, FIAT_DECIMALS), self.currency, self.details)
+    }
+
+    /// Identifies the credit so importing a statement twice is harmless.
+    /// Without an entry reference identical wires on the same day are told
+    /// apart by `occurrence`, their position among the identical entries.
+    fn fingerprint(&self, occurrence: usize) -> String {
+        let mut sha = Sha256::new();
+        match self.entry_reference {
+            Some(ref r) => sha.input(format!("ref\n{}", r).as_bytes()),
+            None => sha.input(format!("{}\n{}\n{}\n{}\n{}", self.date, self.amount, self.currency, self.details, occurrence).as_bytes()),
+        }
+        hex::encode(sha.result())
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Reconciliation {
+    pub matched: Vec<String>,
+    pub review: Vec<String>,
+    pub unmatched: Vec<String>,
+    pub duplicates: usize,
+}
+
+pub(crate) fn read_statement(path: &Path, format: Option<StatementFormat>) -> Result<Vec<Credit>, String> {
+    let format = match format {
+        Some(f) => f,
+        None => match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("csv") => StatementFormat::Csv,
+            Some(e) if e.eq_ignore_ascii_case("xml") => StatementFormat::Camt053,
+            _ => return Err("Unable to tell the statement format, use --format".to_string()),
+        },
+    };
+    let contents = fs::read_to_string(path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+    match format {
+        StatementFormat::Csv => parse_csv(&contents),
+        StatementFormat::Camt053 => parse_camt053(&contents),
+    }
+}
+
+/// Expects a header row with date, amount, currency and reference columns
+/// and optionally the bank's transaction id. Debits (negative amounts) are
+/// skipped.
+fn parse_csv(contents: &str) -> Result<Vec<Credit>, String> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|h| h.trim().to_lowercase())
+        .collect::<Vec<String>>();
+    let column = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|h| names.contains(&h.as_str()))
+            .ok_or_else(|| format!("Statement has no {} column", names[0]))
+    };
+    let date = column(&["date", "booking date", "value date"])?;
+    let amount = column(&["amount", "credit"])?;
+    let currency = column(&["currency", "ccy"])?;
+    let reference = column(&["reference", "description", "details", "remittance information"])?;
+    let entry_reference = column(&["transaction id", "entry reference", "bank reference"]).ok();
+
+    let mut credits = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        let value = record.get(amount).unwrap_or("").trim();
+        if value.is_empty() || value.starts_with('-') {
+            continue;
+        }
+        credits.push(Credit {
+            date: record.get(date).unwrap_or("").trim().to_string(),
+            amount: parse_amount(&normalize_amount(value.trim_start_matches('+'))?, FIAT_DECIMALS)?,
+            currency: record.get(currency).unwrap_or("").trim().to_uppercase(),
+            details: record.get(reference).unwrap_or("").trim().to_string(),
+            entry_reference: entry_reference
+                .and_then(|i| record.get(i))
+                .map(|r| r.trim().to_string())
+                .filter(|r| !r.is_empty()),
+        });
+    }
+    Ok(credits)
+}
+
+/// Statements use a dot or a comma as the decimal separator. The last
+/// separator is the decimal one when both appear and a single comma before
+/// two final digits is one too, any other separator must group thousands.
+fn normalize_amount(value: &str) -> Result<String, String> {
+    let err = || format!("Ambiguous amount {}", value);
+    let decimal = match (value.rfind('.'), value.rfind(',')) {
+        (Some(d), Some(c)) => Some(d.max(c)),
+        (Some(d), None) if value.matches('.').count() == 1 => Some(d),
+        (None, Some(c)) if value.matches(',').count() == 1 && value.len() - c == 3 => Some(c),
+        _ => None,
+    };
+    let (whole, fraction) = match decimal {
+        Some(i) => (&value[..i], &value[i + 1..]),
+        None => (value, ""),
+    };
+    if decimal.map_or(false, |i| whole.contains(&value[i..=i])) || (whole.contains('.') && whole.contains(',')) {
+        return Err(err());
+    }
+    let groups = whole.split(|c| c == '.' || c == ',').collect::<Vec<&str>>();
+    if groups.len() > 1 && (groups[0].is_empty() || groups[0].len() > 3 || groups[1..].iter().any(|g| g.len() != 3)) {
+        return Err(err());
+    }
+    match decimal {
+        Some(_) => Ok(format!("{}.{}", groups.concat(), fraction)),
+        None => Ok(groups.concat()),
+    }
+}
+
+/// ISO 20022 BkToCstmrStmt, only credit entries are read
+fn parse_camt053(contents: &str) -> Result<Vec<Credit>, String> {
+    let document = roxmltree::Document::parse(contents).map_err(|e| e.to_string())?;
+    let mut credits = Vec::new();
+    for entry in document.descendants().filter(|n| n.has_tag_name("Ntry")) {
+        if child_text(entry, &["CdtDbtInd"]) != Some("CRDT") {
+            continue;
+        }
+        let amount_node = child(entry, &["Amt"]).ok_or_else(|| "Entry is missing Amt".to_string())?;
+        let details = entry
+            .descendants()
+            .filter(|n| n.has_tag_name("Ustrd") || n.has_tag_name("Ref"))
+            .filter_map(|n| n.text())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        credits.push(Credit {
+            date: child_text(entry, &["BookgDt", "Dt"])
+                .or_else(|| child_text(entry, &["BookgDt", "DtTm"]))
+                .unwrap_or("")
+                .to_string(),
+            amount: parse_amount(amount_node.text().unwrap_or("").trim(), FIAT_DECIMALS)?,
+            currency: amount_node.attribute("Ccy").unwrap_or("").to_uppercase(),
+            details,
+            entry_reference: child_text(entry, &["AcctSvcrRef"])
+                .or_else(|| child_text(entry, &["NtryRef"]))
+                .filter(|r| !r.is_empty())
+                .map(str::to_string),
+        });
+    }
+    Ok(credits)
+}
+
+fn child<'a, 'input>(node: roxmltree::Node<'a, 'input>, path: &[&str]) -> Option<roxmltree::Node<'a, 'input>> {
+    path.iter()
+        .try_fold(node, |n, name| n.children().find(|c| c.has_tag_name(*name)))
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, path: &[&str]) -> Option<&'a str> {
+    child(node, path).and_then(|n| n.text()).map(str::trim)
+}
+
+/// Apply statement credits to open fiat orders. Exact matches are marked
+/// paid, anything else that names an order is flagged for review.
+pub(crate) fn reconcile(store: &Store, credits: &[Credit]) -> Result<Reconciliation, String> {
+    let mut result = Reconciliation::default();
+    let mut occurrences = HashMap::new();
+    for credit in credits {
+        let summary = credit.summary();
+        let occurrence = occurrences.entry(credit.fingerprint(0)).or_insert(0);
+        let fingerprint = credit.fingerprint(*occurrence);
+        *occurrence += 1;
+
+        let order = match find_payment_reference(&credit.details) {
+            Some(r) => store.find_by_payment_reference(&r)?,
+            None => None,
+        };
+        let order = match order {
+            Some(o) if o.payment_method == PaymentMethod::Fiat => o,
+            _ => {
+                if store.record_statement_credit(&fingerprint)? {
+                    result.unmatched.push(summary);
+                } else {
+                    result.duplicates += 1;
+                }
+                continue;
+            }
+        };
+
+        let order = store.apply_statement_credit(&fingerprint, &order.id, |o| {
+            // Not credited, a person has to work out what it is worth
+            if !credit.currency.eq_ignore_ascii_case(&o.currency) {
+                o.state = OrderState::Review;
+                o.notes.push(format!("Received {} in {}, expected {}", summary, credit.currency, o.currency));
+                return;
+            }
+            let received = o.amount_received.saturating_add(credit.amount);
+            o.amount_received = received;
+            o.amount_seen = received;
+            if received == o.amount_due && o.state != OrderState::Review {
+                o.state = OrderState::Paid;
+            } else {
+                o.state = OrderState::Review;
+                o.notes.push(format!("Received {}, total {} of {} due", summary, received, o.amount_due));
+            }
+        })?;
+        match order {
+            None => result.duplicates += 1,
+            Some(ref o) if o.state == OrderState::Paid => result.matched.push(format!("{} => order {}", summary, o.id)),
+            Some(ref o) => result.review.push(format!("{} => order {}", summary, o.id)),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::Order;
+
+    #[test]
+    fn payment_reference_round_trip() {
+        // Example from ISO 11649
+        assert!(is_valid_reference("RF18539007547034"));
+        assert!(!is_valid_reference("RF19539007547034"));
+
+        let reference = generate_payment_reference();
+        assert!(is_valid_reference(&reference));
+        let text = format!("invoice {} thanks", reference.to_lowercase());
+        assert_eq!(find_payment_reference(&text), Some(reference));
+    }
+
+    #[test]
+    fn statements_are_parsed() {
+        let csv = "Date,Amount,Currency,Reference,Transaction ID\n\
+                   2020-01-02,\"1,000.50\",usd,Tokens RF18 5390 0754 7034,T1\n\
+                   2020-01-02,-20.00,USD,Fees,T2\n\
+                   2020-01-03,+5,EUR,Unknown,\n";
+        let credits = parse_csv(csv).unwrap();
+        assert_eq!(credits.len(), 2);
+        assert_eq!((credits[0].amount, credits[0].currency.as_str()), (100_050, "USD"));
+        assert_eq!(credits[0].entry_reference, Some("T1".to_string()));
+        assert_eq!((credits[1].amount, credits[1].entry_reference.clone()), (500, None));
+        assert!(parse_csv("Date,Amount,Currency\n").is_err());
+        assert!(parse_csv("Date,Amount,Currency,Reference\n2020-01-02,1.234,USD,x\n").is_err());
+
+        for &(value, expected) in &[("1,234.56", 123_456), ("12,50", 1250), ("1.234,56", 123_456), ("1,000", 100_000), ("1.234.567,8", 123_456_780)] {
+            let csv = format!("Date,Amount,Currency,Reference\n2020-01-02,\"{}\",EUR,x\n", value);
+            assert_eq!(parse_csv(&csv).unwrap()[0].amount, expected, "{}", value);
+        }
+        for value in &["12,5", "1,23,45", "1.234.56", "1,234,56", ",50", "1.234,567"] {
+            let csv = format!("Date,Amount,Currency,Reference\n2020-01-02,\"{}\",EUR,x\n", value);
+            assert!(parse_csv(&csv).is_err(), "{}", value);
+        }
+
+        let camt = r#"<?xml version="1.0"?>
+            <Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.02"><BkToCstmrStmt><Stmt>
+              <Ntry>
+                <NtryRef>E1</NtryRef>
+                <Amt Ccy="usd">250.00</Amt>
+                <CdtDbtInd>CRDT</CdtDbtInd>
+                <BookgDt><Dt>2020-01-02</Dt></BookgDt>
+                <NtryDtls><TxDtls><RmtInf><Ustrd>Tokens</Ustrd><Ustrd>RF18539007547034</Ustrd></RmtInf></TxDtls></NtryDtls>
+              </Ntry>
+              <Ntry>
+                <Amt Ccy="USD">10.00</Amt>
+                <CdtDbtInd>DBIT</CdtDbtInd>
+                <BookgDt><Dt>2020-01-02</Dt></BookgDt>
+              </Ntry>
+            </Stmt></BkToCstmrStmt></Document>"#;
+        let credits = parse_camt053(camt).unwrap();
+        assert_eq!(credits.len(), 1);
+        assert_eq!((credits[0].amount, credits[0].currency.as_str(), credits[0].date.as_str()), (25_000, "USD", "2020-01-02"));
+        assert_eq!(credits[0].details, "Tokens RF18539007547034");
+        assert_eq!(credits[0].entry_reference, Some("E1".to_string()));
+        assert!(parse_camt053("<Document>").is_err());
+    }
+
+    #[test]
+    fn credits_are_applied_once() {
+        let store = Store::temporary().unwrap();
+        let application = store.create_application().unwrap();
+        let reference = generate_payment_reference();
+        let order = Order {
+            id: "order".to_string(),
+            application_id: application.id,
+            created: 0,
+            tokens: 100,
+            payment_method: PaymentMethod::Fiat,
+            amount_due: 100,
+            currency: "USD".to_string(),
+            state: OrderState::Pending,
+            deposit: None,
+            amount_received: 0,
+            amount_seen: 0,
+            transactions: Vec::new(),
+            payment_reference: Some(reference.clone()),
+            notes: Vec::new(),
+            authorized_at: None,
+            test: false,
+        };
+        store.create_order(&order).unwrap();
+        let credit = |details: &str| Credit {
+            date: "2020-01-02".to_string(),
+            amount: 100,
+            currency: "USD".to_string(),
+            details: details.to_string(),
+            entry_reference: None,
+        };
+
+        // Two identical wires on one day are both counted
+        let statement = vec![credit(&reference), credit(&reference), credit("no reference")];
+        let result = reconcile(&store, &statement).unwrap();
+        assert_eq!((result.matched.len(), result.review.len(), result.unmatched.len(), result.duplicates), (1, 1, 1, 0));
+        assert_eq!(store.get_order("order").unwrap().unwrap().amount_received, 200);
+
+        let again = reconcile(&store, &statement).unwrap();
+        assert_eq!((again.matched.len(), again.review.len(), again.unmatched.len(), again.duplicates), (0, 0, 0, 3));
+        assert_eq!(store.get_order("order").unwrap().unwrap().amount_received, 200);
+
+        // Flagged but not credited in another currency
+        let euros = Credit { currency: "EUR".to_string(), ..credit(&reference) };
+        let result = reconcile(&store, &[euros]).unwrap();
+        assert_eq!(result.review.len(), 1);
+        let order = store.get_order("order").unwrap().unwrap();
+        assert_eq!((order.state, order.amount_received), (OrderState::Review, 200));
+
+        // A credit that fails to apply is not remembered
+        let missing = credit("other").fingerprint(0);
+        assert!(store.apply_statement_credit(&missing, "missing", |o| o.amount_received = 1).is_err());
+        assert!(store.record_statement_credit(&missing).unwrap());
+    }
+}
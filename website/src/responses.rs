@@ -1,8 +1,21 @@
+use crate::challenge::Purpose;
+use sovrin_signing::Scheme;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub(crate) struct PaymentAddressChallengeResponse {
+    /// The application to record the proven address against
+    pub application_id: Option<String>,
     pub address: String,
     pub challenge: String,
-    pub signature: String
+    pub signature: String,
+    /// How `signature` was made, defaults to the prefixed SHA-256 hash
+    /// encoded as base64url
+    #[serde(default)]
+    pub scheme: Scheme,
+    /// Must match the purpose the challenge was issued for
+    #[serde(default)]
+    pub purpose: Purpose,
+    /// Must match the order the challenge was issued for
+    pub order_id: Option<String>
 }
@@ -0,0 +1,62 @@
+use crate::{
+    api::{ApiError, ApiResponse, ApiResult},
+    config::Config,
+    deposits::DepositAddresses,
+};
+use bitcoin::Network;
+use celes::Country;
+use rocket::State;
+use std::collections::BTreeMap;
+use trulioo::{mock::MockServer, Entity, TruliooRequest};
+
+/// Started with --test. Identity checks go to the Trulioo trial gateway, or
+/// to the recorded fixtures when there is no trial key. The database,
+/// documents, deposit keys and watched nodes are all separate from
+/// production, and everything stored is marked as test data.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Sandbox(pub bool);
+
+/// Only `sandbox_trulioo` is used, the production key never goes to the
+/// trial gateway. Without it the fixture server stands in for Trulioo and
+/// must outlive the site.
+pub(crate) fn trulioo_request(config: &Config) -> (TruliooRequest, Option<MockServer>) {
+    if let Some(ref t) = config.sandbox_trulioo {
+        let mut config = config.clone();
+        config.trulioo = Some(crate::config::Trulioo { url: trulioo::TRIAL_BASE_URL.to_string(), ..t.clone() });
+        return (crate::get_trulioo_request(&config), None);
+    }
+    let mock = match MockServer::start(trulioo::mock::FIXTURES) {
+        Err(why) => panic!("Unable to start the Trulioo fixture server: {}", why),
+        Ok(m) => m
+    };
+    eprintln!("Sandbox mode: answering Trulioo calls from {}", trulioo::mock::FIXTURES);
+    (mock.request(), Some(mock))
+}
+
+/// Test orders are given addresses from `sandbox_deposits`, never from the
+/// production keys where they would take up real addresses
+pub(crate) fn deposit_addresses(config: &Config) -> Result<DepositAddresses, String> {
+    let deposits = config.sandbox_deposits.clone().unwrap_or_default();
+    if let Some(ref production) = config.deposits {
+        let reused = |test: &Option<String>, real: &Option<String>| test.is_some() && test == real;
+        if reused(&deposits.bitcoin_xpub, &production.bitcoin_xpub) || reused(&deposits.ether_xpub, &production.ether_xpub) {
+            return Err("Sandbox deposits cannot use the production xpubs".to_string());
+        }
+    }
+    let addresses = DepositAddresses::new(&deposits)?;
+    if addresses.bitcoin_network() == Some(Network::Bitcoin) {
+        return Err("Sandbox bitcoin deposits must be on a test network".to_string());
+    }
+    Ok(addresses)
+}
+
+/// Made up people Trulioo's trial configuration knows about, only mounted
+/// in sandbox mode
+#[get("/test_entities/<country>")]
+pub(crate) fn get_test_entities(country: String, request: State<TruliooRequest>, countries: State<BTreeMap<String, Country>>) -> ApiResult<Vec<Entity>> {
+    if !countries.inner().contains_key(&country) {
+        return Err(ApiError::bad_request("Invalid country code"));
+    }
+    let entities = async_std::task::block_on(request.inner().get_test_entities(country))?;
+    ApiResponse::ok(entities.into_iter().flatten().collect())
+}
@@ -0,0 +1,568 @@
+use crate::{
+    deposits::{DepositAddress, DepositAddresses},
+    kyc::KycResult,
+    orders::{Order, OrderState},
+    quotes::CryptoCurrency,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional,
+};
+use std::path::Path;
+
+const META: &str = "meta";
+const SCHEMA_VERSION: &str = "schema_version";
+const APPLICATIONS: &str = "applications";
+const KYC_TRANSACTIONS: &str = "kyc_transactions";
+const ORDERS: &str = "orders";
+const DEPOSIT_INDICES: &str = "deposit_indices";
+const DEPOSIT_ADDRESSES: &str = "deposit_addresses";
+const PAYMENT_REFERENCES: &str = "payment_references";
+const STATEMENT_CREDITS: &str = "statement_credits";
+const CHALLENGE_NONCES: &str = "challenge_nonces";
+const WATCH_CURSORS: &str = "watch_cursors";
+const CHALLENGE_NONCE_EXPIRY: &str = "challenge_nonce_expiry";
+const REDEEMED_QUOTES: &str = "redeemed_quotes";
+const REDEEMED_QUOTE_EXPIRY: &str = "redeemed_quote_expiry";
+
+/// Each entry upgrades the schema by one version. Never reorder or remove
+/// entries, only append.
+const MIGRATIONS: &[fn(&sled::Db) -> sled::Result<()>] = &[
+    migrate_v1,
+    migrate_v2,
+    migrate_v3,
+    migrate_v4,
+    migrate_v5,
+    migrate_v6,
+    migrate_v7,
+    migrate_v8,
+];
+
+/// Everything known about one purchaser as they move through the site
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Application {
+    pub id: String,
+    pub created: u64,
+    /// The `pay:sov:` address proven with a signed challenge, always in
+    /// canonical form see `sovrin_signing::decode_payment_address`
+    pub payment_address: Option<String>,
+    pub address_verified_at: Option<u64>,
+    pub consents: Vec<String>,
+    pub individual: Option<KycResult>,
+    pub business: Option<KycResult>,
+    pub document: Option<KycResult>,
+    /// Where the images behind `document` are kept in the document store
+    #[serde(default)]
+    pub document_reference: Option<String>,
+    pub orders: Vec<String>,
+    /// Created in sandbox mode, not a real purchaser
+    #[serde(default)]
+    pub test: bool,
+}
+
+/// Embedded database holding applications. Clones share the same database.
+#[derive(Clone)]
+pub(crate) struct Store {
+    db: sled::Db,
+    applications: sled::Tree,
+    kyc_transactions: sled::Tree,
+    orders: sled::Tree,
+    deposit_indices: sled::Tree,
+    deposit_addresses: sled::Tree,
+    payment_references: sled::Tree,
+    statement_credits: sled::Tree,
+    challenge_nonces: sled::Tree,
+    /// Big endian expiry followed by the nonce, so expired nonces sort first
+    challenge_nonce_expiry: sled::Tree,
+    redeemed_quotes: sled::Tree,
+    /// Same layout as `challenge_nonce_expiry`
+    redeemed_quote_expiry: sled::Tree,
+    watch_cursors: sled::Tree,
+    /// Marks everything created as test data
+    sandbox: bool,
+}
+
+impl Store {
+    /// Open the database at `path` and bring its schema up to date
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        Self::from_db(sled::open(path).map_err(|e| e.to_string())?)
+    }
+
+    /// A database that is deleted when dropped
+    #[cfg(test)]
+    pub fn temporary() -> Result<Self, String> {
+        Self::from_db(sled::Config::new().temporary(true).open().map_err(|e| e.to_string())?)
+    }
+
+    fn from_db(db: sled::Db) -> Result<Self, String> {
+        migrate(&db)?;
+        let applications = db.open_tree(APPLICATIONS).map_err(|e| e.to_string())?;
+        let kyc_transactions = db.open_tree(KYC_TRANSACTIONS).map_err(|e| e.to_string())?;
+        let orders = db.open_tree(ORDERS).map_err(|e| e.to_string())?;
+        let deposit_indices = db.open_tree(DEPOSIT_INDICES).map_err(|e| e.to_string())?;
+        let deposit_addresses = db.open_tree(DEPOSIT_ADDRESSES).map_err(|e| e.to_string())?;
+        let payment_references = db.open_tree(PAYMENT_REFERENCES).map_err(|e| e.to_string())?;
+        let statement_credits = db.open_tree(STATEMENT_CREDITS).map_err(|e| e.to_string())?;
+        let challenge_nonces = db.open_tree(CHALLENGE_NONCES).map_err(|e| e.to_string())?;
+        let challenge_nonce_expiry = db.open_tree(CHALLENGE_NONCE_EXPIRY).map_err(|e| e.to_string())?;
+        let redeemed_quotes = db.open_tree(REDEEMED_QUOTES).map_err(|e| e.to_string())?;
+        let redeemed_quote_expiry = db.open_tree(REDEEMED_QUOTE_EXPIRY).map_err(|e| e.to_string())?;
+        let watch_cursors = db.open_tree(WATCH_CURSORS).map_err(|e| e.to_string())?;
+        Ok(Self {
+            db,
+            applications,
+            kyc_transactions,
+            orders,
+            deposit_indices,
+            deposit_addresses,
+            payment_references,
+            statement_credits,
+            challenge_nonces,
+            challenge_nonce_expiry,
+            redeemed_quotes,
+            redeemed_quote_expiry,
+            watch_cursors,
+            sandbox: false,
+        })
+    }
+
+    /// Mark new applications and orders as test data
+    pub fn sandboxed(mut self) -> Self {
+        self.sandbox = true;
+        self
+    }
+
+    pub fn sandbox(&self) -> bool {
+        self.sandbox
+    }
+
+    pub fn create_application(&self) -> Result<Application, String> {
+        let application = Application {
+            id: crate::kyc::generate_reference_id(),
+            created: crate::generate_timestamp()?,
+            test: self.sandbox,
+            ..Application::default()
+        };
+        put(&self.applications, &application.id, &application)?;
+        self.flush()?;
+        Ok(application)
+    }
+
+    pub fn get_application(&self, id: &str) -> Result<Option<Application>, String> {
+        get(&self.applications, id)
+    }
+
+    /// Find the application that a KYC transaction id belongs to
+    pub fn find_by_transaction(&self, transaction_id: &str) -> Result<Option<Application>, String> {
+        match self.kyc_transactions.get(transaction_id).map_err(|e| e.to_string())? {
+            None => Ok(None),
+            Some(id) => self.get_application(&String::from_utf8_lossy(&id)),
+        }
+    }
+
+    /// Change the application `id` with `f`, indexing any KYC transaction
+    /// ids it now holds so `find_by_transaction` finds it
+    pub fn update_application<F>(&self, id: &str, mut f: F) -> Result<Application, String>
+    where
+        F: FnMut(&mut Application),
+    {
+        let application: Application = update(&self.applications, id, &mut f)?;
+        for transaction_id in application
+            .individual
+            .iter()
+            .chain(application.business.iter())
+            .chain(application.document.iter())
+            .filter_map(|r| r.transaction_id.as_ref())
+        {
+            self.kyc_transactions
+                .insert(transaction_id.as_bytes(), application.id.as_bytes())
+                .map_err(|e| e.to_string())?;
+        }
+        self.flush()?;
+        Ok(application)
+    }
+
+    /// Save a new order and add it to its application
+    pub fn create_order(&self, order: &Order) -> Result<(), String> {
+        if let Some(ref reference) = order.payment_reference {
+            let claimed = self.payment_references
+                .compare_and_swap(reference.as_bytes(), None as Option<&[u8]>, Some(order.id.as_bytes()))
+                .map_err(|e| e.to_string())?;
+            if claimed.is_err() {
+                return Err("Payment reference is already in use".to_string());
+            }
+        }
+        put(&self.orders, &order.id, order)?;
+        let id = order.id.clone();
+        self.update_application(&order.application_id, |a| {
+            if !a.orders.contains(&id) {
+                a.orders.push(id.clone());
+            }
+        })?;
+        Ok(())
+    }
+
+    pub fn get_order(&self, id: &str) -> Result<Option<Order>, String> {
+        get(&self.orders, id)
+    }
+
+    pub fn find_by_payment_reference(&self, reference: &str) -> Result<Option<Order>, String> {
+        match self.payment_references.get(reference).map_err(|e| e.to_string())? {
+            None => Ok(None),
+            Some(id) => self.get_order(&String::from_utf8_lossy(&id)),
+        }
+    }
+
+    /// Remember a bank statement credit that matched no order, false if it
+    /// was already imported
+    pub fn record_statement_credit(&self, fingerprint: &str) -> Result<bool, String> {
+        let claimed = self.statement_credits
+            .compare_and_swap(fingerprint, None as Option<&[u8]>, Some(crate::generate_timestamp()?.to_be_bytes().to_vec()))
+            .map_err(|e| e.to_string())?;
+        self.flush()?;
+        Ok(claimed.is_ok())
+    }
+
+    /// Apply a bank statement credit to the order `id` and remember it in
+    /// one transaction, so a credit that fails to apply can be imported
+    /// again. None if it was already imported.
+    pub fn apply_statement_credit<F>(&self, fingerprint: &str, id: &str, f: F) -> Result<Option<Order>, String>
+    where
+        F: Fn(&mut Order),
+    {
+        let imported_at = crate::generate_timestamp()?.to_be_bytes().to_vec();
+        let abort = |e: String| ConflictableTransactionError::Abort(e);
+        let result = (&self.statement_credits, &self.orders).transaction(|(credits, orders)| {
+            if credits.get(fingerprint)?.is_some() {
+                return Ok(None);
+            }
+            let mut order: Order = match orders.get(id)? {
+                None => return Err(abort(format!("{} not found", id))),
+                Some(v) => serde_json::from_slice(&v).map_err(|e| abort(e.to_string()))?,
+            };
+            f(&mut order);
+            let bytes = serde_json::to_vec(&order).map_err(|e| abort(e.to_string()))?;
+            orders.insert(id, bytes)?;
+            credits.insert(fingerprint, imported_at.clone())?;
+            Ok(Some(order))
+        });
+        let order = result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.to_string(),
+        })?;
+        self.flush()?;
+        Ok(order)
+    }
+
+    /// Mark a challenge nonce as used until `expires_at`, false if it
+    /// already was. Nonces past their expiry are dropped along the way.
+    pub fn redeem_challenge_nonce(&self, nonce: &[u8], expires_at: u64) -> Result<bool, String> {
+        let claimed = redeem(&self.challenge_nonces, &self.challenge_nonce_expiry, nonce, expires_at)?;
+        // A redeemed nonce has to survive a crash or it could be replayed
+        self.flush()?;
+        Ok(claimed)
+    }
+
+    /// Mark a quote as used for an order, false if one was already placed
+    /// with it
+    pub fn redeem_quote(&self, id: &str, expires_at: u64) -> Result<bool, String> {
+        let claimed = redeem(&self.redeemed_quotes, &self.redeemed_quote_expiry, id.as_bytes(), expires_at)?;
+        self.flush()?;
+        Ok(claimed)
+    }
+
+    /// The next block the payment watcher scans on `chain`
+    pub fn watch_cursor(&self, chain: &str) -> Result<Option<u64>, String> {
+        let cursor = self.watch_cursors.get(chain).map_err(|e| e.to_string())?;
+        Ok(cursor.map(|c| u64::from_be_bytes(*array_ref!(c, 0, 8))))
+    }
+
+    pub fn set_watch_cursor(&self, chain: &str, block: u64) -> Result<(), String> {
+        self.watch_cursors.insert(chain, block.to_be_bytes().to_vec()).map_err(|e| e.to_string())?;
+        self.flush()
+    }
+
+    /// Open orders, and orders placed since `paid_since` that are paid but
+    /// could still be overpaid
+    pub fn watched_orders(&self, paid_since: u64) -> Result<Vec<Order>, String> {
+        let mut orders = Vec::new();
+        for entry in self.orders.iter() {
+            let (_, value) = entry.map_err(|e| e.to_string())?;
+            let order: Order = serde_json::from_slice(&value).map_err(|e| e.to_string())?;
+            let paid = order.state == OrderState::Paid || order.state == OrderState::Overpaid;
+            if order.state.is_open() || (paid && order.created >= paid_since) {
+                orders.push(order);
+            }
+        }
+        Ok(orders)
+    }
+
+    /// Change the order `id` with `f` and flush, orders carry payments
+    pub fn update_order<F>(&self, id: &str, mut f: F) -> Result<Order, String>
+    where
+        F: FnMut(&mut Order),
+    {
+        let order = update(&self.orders, id, &mut f)?;
+        self.flush()?;
+        Ok(order)
+    }
+
+    /// Derive the next unused deposit address for `order_id` and record
+    /// its derivation index
+    pub fn allocate_deposit_address(&self, currency: CryptoCurrency, order_id: &str, addresses: &DepositAddresses) -> Result<DepositAddress, String> {
+        loop {
+            let next = self.deposit_indices
+                .update_and_fetch(currency.code(), |old| {
+                    let index = old.map(|v| u32::from_be_bytes(*array_ref!(v, 0, 4)) + 1).unwrap_or(0);
+                    Some(index.to_be_bytes().to_vec())
+                })
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| "Unable to allocate a deposit index".to_string())?;
+            let index = u32::from_be_bytes(*array_ref!(next, 0, 4));
+            let deposit = addresses.derive(currency, index)?;
+            let claimed = self.deposit_addresses
+                .compare_and_swap(deposit.address.as_bytes(), None as Option<&[u8]>, Some(order_id.as_bytes()))
+                .map_err(|e| e.to_string())?;
+            if claimed.is_ok() {
+                self.flush()?;
+                return Ok(deposit);
+            }
+        }
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.db.flush().map(|_| ()).map_err(|e| e.to_string())
+    }
+}
+
+fn migrate(db: &sled::Db) -> Result<(), String> {
+    let meta = db.open_tree(META).map_err(|e| e.to_string())?;
+    let mut version = match meta.get(SCHEMA_VERSION).map_err(|e| e.to_string())? {
+        Some(v) => u32::from_be_bytes(*array_ref!(v, 0, 4)),
+        None => 0,
+    };
+    if version as usize > MIGRATIONS.len() {
+        return Err(format!("Database schema version {} is newer than this binary supports", version));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(db).map_err(|e| format!("Migration to schema version {} failed: {}", version + 1, e))?;
+        version += 1;
+        meta.insert(SCHEMA_VERSION, version.to_be_bytes().to_vec()).map_err(|e| e.to_string())?;
+    }
+    db.flush().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn migrate_v1(db: &sled::Db) -> sled::Result<()> {
+    db.open_tree(APPLICATIONS)?;
+    db.open_tree(KYC_TRANSACTIONS)?;
+    Ok(())
+}
+
+fn migrate_v2(db: &sled::Db) -> sled::Result<()> {
+    db.open_tree(ORDERS)?;
+    Ok(())
+}
+
+fn migrate_v3(db: &sled::Db) -> sled::Result<()> {
+    db.open_tree(DEPOSIT_INDICES)?;
+    db.open_tree(DEPOSIT_ADDRESSES)?;
+    Ok(())
+}
+
+fn migrate_v4(db: &sled::Db) -> sled::Result<()> {
+    db.open_tree(PAYMENT_REFERENCES)?;
+    db.open_tree(STATEMENT_CREDITS)?;
+    Ok(())
+}
+
+fn migrate_v5(db: &sled::Db) -> sled::Result<()> {
+    db.open_tree(CHALLENGE_NONCES)?;
+    Ok(())
+}
+
+fn migrate_v6(db: &sled::Db) -> sled::Result<()> {
+    db.open_tree(WATCH_CURSORS)?;
+    Ok(())
+}
+
+/// Index the nonces redeemed so far by expiry
+fn migrate_v7(db: &sled::Db) -> sled::Result<()> {
+    let nonces = db.open_tree(CHALLENGE_NONCES)?;
+    let expiry = db.open_tree(CHALLENGE_NONCE_EXPIRY)?;
+    for entry in nonces.iter() {
+        let (nonce, expires_at) = entry?;
+        expiry.insert(nonce_expiry_key(&nonce, u64::from_be_bytes(*array_ref!(expires_at, 0, 8))), Vec::new())?;
+    }
+    Ok(())
+}
+
+fn migrate_v8(db: &sled::Db) -> sled::Result<()> {
+    db.open_tree(REDEEMED_QUOTES)?;
+    db.open_tree(REDEEMED_QUOTE_EXPIRY)?;
+    Ok(())
+}
+
+/// Claim `key` in `values` until `expires_at`, dropping expired claims
+/// through the `expiry` index
+fn redeem(values: &sled::Tree, expiry: &sled::Tree, key: &[u8], expires_at: u64) -> Result<bool, String> {
+    let now = crate::generate_timestamp()?;
+    for entry in expiry.range(..now.to_be_bytes()) {
+        let (expired, _) = entry.map_err(|e| e.to_string())?;
+        values.remove(&expired[8..]).map_err(|e| e.to_string())?;
+        expiry.remove(expired).map_err(|e| e.to_string())?;
+    }
+    let claimed = values
+        .compare_and_swap(key, None as Option<&[u8]>, Some(expires_at.to_be_bytes().to_vec()))
+        .map_err(|e| e.to_string())?;
+    if claimed.is_ok() {
+        expiry.insert(nonce_expiry_key(key, expires_at), Vec::new()).map_err(|e| e.to_string())?;
+    }
+    Ok(claimed.is_ok())
+}
+
+fn nonce_expiry_key(nonce: &[u8], expires_at: u64) -> Vec<u8> {
+    let mut key = expires_at.to_be_bytes().to_vec();
+    key.extend_from_slice(nonce);
+    key
+}
+
+pub(crate) fn get<T: DeserializeOwned>(tree: &sled::Tree, key: &str) -> Result<Option<T>, String> {
+    match tree.get(key).map_err(|e| e.to_string())? {
+        None => Ok(None),
+        Some(v) => serde_json::from_slice(&v).map(Some).map_err(|e| e.to_string()),
+    }
+}
+
+pub(crate) fn put<T: Serialize>(tree: &sled::Tree, key: &str, value: &T) -> Result<(), String> {
+    let bytes = serde_json::to_vec(value).map_err(|e| e.to_string())?;
+    tree.insert(key, bytes).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Read-modify-write `key` in `tree`. `f` may be called more than once if
+/// the record is changed concurrently.
+pub(crate) fn update<T, F>(tree: &sled::Tree, key: &str, mut f: F) -> Result<T, String>
+where
+    T: DeserializeOwned + Serialize,
+    F: FnMut(&mut T),
+{
+    let mut error = None;
+    let result = tree
+        .update_and_fetch(key, |old| {
+            let old = old?;
+            match serde_json::from_slice::<T>(old) {
+                Ok(mut value) => {
+                    f(&mut value);
+                    Some(serde_json::to_vec(&value).unwrap_or_else(|_| old.to_vec()))
+                }
+                Err(e) => {
+                    error = Some(e.to_string());
+                    Some(old.to_vec())
+                }
+            }
+        })
+        .map_err(|e| e.to_string())?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+    match result {
+        None => Err(format!("{} not found", key)),
+        Some(v) => serde_json::from_slice(&v).map_err(|e| e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Deposits, kyc::KycStatus};
+
+    // BIP 32 test vector 1
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn migrations_are_applied_once() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        migrate(&db).unwrap();
+        migrate(&db).unwrap();
+        let meta = db.open_tree(META).unwrap();
+        let version = meta.get(SCHEMA_VERSION).unwrap().unwrap();
+        assert_eq!(u32::from_be_bytes(*array_ref!(version, 0, 4)) as usize, MIGRATIONS.len());
+        assert!(db.tree_names().iter().any(|n| n.as_ref() == CHALLENGE_NONCES.as_bytes()));
+
+        meta.insert(SCHEMA_VERSION, (MIGRATIONS.len() as u32 + 1).to_be_bytes().to_vec()).unwrap();
+        assert!(migrate(&db).is_err());
+    }
+
+    #[test]
+    fn old_databases_are_brought_up_to_date() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        migrate_v1(&db).unwrap();
+        let meta = db.open_tree(META).unwrap();
+        meta.insert(SCHEMA_VERSION, 1u32.to_be_bytes().to_vec()).unwrap();
+        let application = Application { id: "v1".to_string(), created: 1, ..Application::default() };
+        put(&db.open_tree(APPLICATIONS).unwrap(), "v1", &application).unwrap();
+
+        let store = Store::from_db(db.clone()).unwrap();
+        let version = meta.get(SCHEMA_VERSION).unwrap().unwrap();
+        assert_eq!(u32::from_be_bytes(*array_ref!(version, 0, 4)) as usize, MIGRATIONS.len());
+        for tree in &[ORDERS, DEPOSIT_ADDRESSES, STATEMENT_CREDITS, CHALLENGE_NONCES, WATCH_CURSORS, CHALLENGE_NONCE_EXPIRY, REDEEMED_QUOTES] {
+            assert!(db.tree_names().iter().any(|n| n.as_ref() == tree.as_bytes()), "{}", tree);
+        }
+        assert_eq!(store.get_application("v1").unwrap().unwrap().created, 1);
+        assert!(store.update_order("missing", |o| o.tokens = 1).is_err());
+    }
+
+    #[test]
+    fn applications_are_updated_and_indexed() {
+        let store = Store::temporary().unwrap();
+        let application = store.create_application().unwrap();
+        assert!(!application.test);
+        assert!(store.clone().sandboxed().create_application().unwrap().test);
+
+        let result = KycResult { status: KycStatus::Match, transaction_id: Some("transaction".to_string()), errors: Vec::new() };
+        let updated = store.update_application(&application.id, |a| a.individual = Some(result.clone())).unwrap();
+        assert_eq!(updated.individual.unwrap().status, KycStatus::Match);
+        assert_eq!(store.find_by_transaction("transaction").unwrap().unwrap().id, application.id);
+        assert!(store.find_by_transaction("other").unwrap().is_none());
+
+        assert!(store.update_application("missing", |a| a.test = true).is_err());
+        assert!(store.get_application("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn deposit_addresses_are_never_reused() {
+        let store = Store::temporary().unwrap();
+        let addresses = DepositAddresses::new(&Deposits { bitcoin_xpub: Some(XPUB.to_string()), ..Deposits::default() }).unwrap();
+        let first = store.allocate_deposit_address(CryptoCurrency::Bitcoin, "first", &addresses).unwrap();
+        let second = store.allocate_deposit_address(CryptoCurrency::Bitcoin, "second", &addresses).unwrap();
+        assert_eq!((first.index, second.index), (0, 1));
+        assert_ne!(first.address, second.address);
+
+        // An address claimed outside the counter is skipped
+        let taken = addresses.derive(CryptoCurrency::Bitcoin, 2).unwrap();
+        store.deposit_addresses.insert(taken.address.as_bytes(), &b"elsewhere"[..]).unwrap();
+        assert_eq!(store.allocate_deposit_address(CryptoCurrency::Bitcoin, "third", &addresses).unwrap().index, 3);
+
+        assert!(store.allocate_deposit_address(CryptoCurrency::Ether, "fourth", &addresses).is_err());
+    }
+
+    #[test]
+    fn nonces_are_single_use_until_they_expire() {
+        let store = Store::temporary().unwrap();
+        let now = crate::generate_timestamp().unwrap();
+        assert!(store.redeem_challenge_nonce(b"nonce", now + 60).unwrap());
+        assert!(!store.redeem_challenge_nonce(b"nonce", now + 60).unwrap());
+        assert!(store.redeem_challenge_nonce(b"other", now + 60).unwrap());
+
+        assert!(store.redeem_challenge_nonce(b"expired", now - 1).unwrap());
+        assert!(store.redeem_challenge_nonce(b"later", now + 60).unwrap());
+        assert!(store.challenge_nonces.get(b"expired").unwrap().is_none());
+        assert_eq!(store.challenge_nonce_expiry.len(), 3);
+
+        assert!(store.redeem_quote("quote", now + 60).unwrap());
+        assert!(!store.redeem_quote("quote", now + 60).unwrap());
+        assert!(store.redeem_challenge_nonce(b"quote", now + 60).unwrap(), "kept apart from nonces");
+    }
+}
@@ -0,0 +1,429 @@
+use crate::{
+    config::{NodeRpc, Watcher},
+    orders::{Order, OrderState},
+    quotes::CryptoCurrency,
+    store::Store,
+};
+use isahc::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// The key of the ether block cursor in the store
+const ETHER: &str = "ether";
+/// Blocks scanned per poll so catching up after downtime does not stall
+const MAX_BLOCKS_PER_POLL: u64 = 1000;
+
+/// The outcome of the last poll, reported by the health check
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct WatcherStatus {
+    pub last_poll: Option<u64>,
+    /// Why the last poll failed, as a whole or for some orders
+    pub last_error: Option<String>,
+}
+
+/// Shared between the watcher thread and the health check
+#[derive(Clone, Default)]
+pub(crate) struct WatcherHealth(Arc<Mutex<WatcherStatus>>);
+
+impl WatcherHealth {
+    pub fn status(&self) -> WatcherStatus {
+        self.0.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    fn record(&self, result: Result<(), String>) {
+        if let Ok(mut status) = self.0.lock() {
+            status.last_poll = crate::generate_timestamp().ok();
+            status.last_error = result.err();
+        }
+    }
+}
+
+/// Start polling the configured nodes for payments to open and recently
+/// paid orders
+pub(crate) fn spawn(config: Watcher, store: Store, health: WatcherHealth) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut watcher = PaymentWatcher {
+            bitcoin: config.bitcoin.clone().map(|n| JsonRpc::new(n, "1.0")),
+            ether: config.ether.clone().map(|n| JsonRpc::new(n, "2.0")),
+            imported: HashSet::new(),
+            config,
+            store,
+        };
+        loop {
+            health.record(watcher.poll());
+            thread::sleep(Duration::from_secs(watcher.config.poll_interval));
+        }
+    })
+}
+
+struct PaymentWatcher {
+    config: Watcher,
+    store: Store,
+    bitcoin: Option<JsonRpc>,
+    ether: Option<JsonRpc>,
+    /// Addresses already imported into bitcoind as watch-only
+    imported: HashSet<String>,
+}
+
+impl PaymentWatcher {
+    fn poll(&mut self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let mut ether_orders = Vec::new();
+        let now = crate::generate_timestamp()?;
+        let paid_since = now.saturating_sub(self.config.overpayment_window);
+        for order in self.store.watched_orders(paid_since)? {
+            let address = match order.deposit {
+                Some(ref d) => d.address.clone(),
+                None => continue,
+            };
+            match CryptoCurrency::from_payment_method(order.payment_method) {
+                Some(CryptoCurrency::Bitcoin) => {
+                    if let Err(why) = self.poll_bitcoin(&order, &address) {
+                        errors.push(format!("order {}: {}", order.id, why));
+                    }
+                }
+                Some(CryptoCurrency::Ether) => ether_orders.push(order),
+                None => {}
+            }
+        }
+        if let Err(why) = self.poll_ether(&ether_orders) {
+            errors.push(format!("ether: {}", why));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    /// bitcoind sums what an address received, spending from it does not
+    /// lower that
+    fn poll_bitcoin(&mut self, order: &Order, address: &str) -> Result<(), String> {
+        let rpc = match self.bitcoin {
+            Some(ref r) => r,
+            None => return Ok(()),
+        };
+        if !self.imported.contains(address) {
+            rpc.call("importaddress", json!([address, "", false]))?;
+            self.imported.insert(address.to_string());
+        }
+        let confirmations = self.config.bitcoin_confirmations.max(1);
+        let confirmed = btc_to_satoshis(&rpc.call("getreceivedbyaddress", json!([address, confirmations]))?)?;
+        let seen = btc_to_satoshis(&rpc.call("getreceivedbyaddress", json!([address, 0]))?)?;
+        if confirmed != order.amount_received || seen != order.amount_seen {
+            self.store.update_order(&order.id, |o| record_payment(o, confirmed, seen))?;
+        }
+        Ok(())
+    }
+
+    /// Credit ether transfers to the deposit addresses of `orders` block by
+    /// block. Balances are no use, sweeping an address lowers them and an
+    /// address can hold funds from before the order. Only plain transfers
+    /// are seen, not ether sent by contracts.
+    fn poll_ether(&self, orders: &[Order]) -> Result<(), String> {
+        let rpc = match self.ether {
+            Some(ref r) => r,
+            None => return Ok(()),
+        };
+        let latest = block_number(&rpc.call("eth_blockNumber", json!([]))?)?;
+        // Blocks before `confirmed_to` have enough confirmations
+        let confirmed_to = (latest + 1).saturating_sub(self.config.ether_confirmations.max(1));
+        let from = match self.store.watch_cursor(ETHER)? {
+            Some(c) => c,
+            None => confirmed_to,
+        };
+        if orders.is_empty() {
+            return self.store.set_watch_cursor(ETHER, from.max(confirmed_to));
+        }
+
+        let by_address = orders
+            .iter()
+            .filter_map(|o| o.deposit.as_ref().map(|d| (d.address.to_lowercase(), o)))
+            .collect::<HashMap<String, &Order>>();
+        let mut pending = HashMap::<String, u64>::new();
+        let to = latest.min(from.saturating_add(MAX_BLOCKS_PER_POLL));
+        for number in from..=to {
+            let block = rpc.call("eth_getBlockByNumber", json!([format!("0x{:x}", number), true]))?;
+            for transfer in ether_transfers(&block)? {
+                let order = match by_address.get(&transfer.to) {
+                    Some(o) if transfer.timestamp >= o.created => o,
+                    _ => continue,
+                };
+                if number < confirmed_to {
+                    self.store.update_order(&order.id, |o| credit_transfer(o, &transfer.hash, transfer.amount))?;
+                } else {
+                    let amount = pending.entry(order.id.clone()).or_insert(0);
+                    *amount = amount.saturating_add(transfer.amount);
+                }
+            }
+            if number < confirmed_to {
+                self.store.set_watch_cursor(ETHER, number + 1)?;
+            }
+        }
+        if to < latest {
+            // Still catching up, unconfirmed blocks are not scanned yet
+            return Ok(());
+        }
+        for order in orders {
+            let unconfirmed = pending.get(&order.id).cloned().unwrap_or(0);
+            self.store.update_order(&order.id, |o| {
+                let seen = o.amount_received.saturating_add(unconfirmed);
+                let confirmed = o.amount_received;
+                record_payment(o, confirmed, seen)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Move the order along according to the confirmed amount received. The
+/// amount received never goes down, a lagging node must not unpay an order.
+pub(crate) fn record_payment(order: &mut Order, confirmed: u64, seen: u64) {
+    order.amount_received = order.amount_received.max(confirmed);
+    order.amount_seen = seen.max(order.amount_received);
+    order.state = if order.amount_received == 0 {
+        OrderState::Pending
+    } else if order.amount_received < order.amount_due {
+        OrderState::PartiallyPaid
+    } else if order.amount_received == order.amount_due {
+        OrderState::Paid
+    } else {
+        OrderState::Overpaid
+    };
+}
+
+/// Add a confirmed transfer to the order unless it was counted already
+pub(crate) fn credit_transfer(order: &mut Order, hash: &str, amount: u64) {
+    if order.transactions.iter().any(|t| t == hash) {
+        return;
+    }
+    order.transactions.push(hash.to_string());
+    let confirmed = order.amount_received.saturating_add(amount);
+    let seen = order.amount_seen;
+    record_payment(order, confirmed, seen);
+}
+
+/// Ether sent by a transaction in a block
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Transfer {
+    hash: String,
+    /// Lowercase address
+    to: String,
+    /// Gwei
+    amount: u64,
+    timestamp: u64,
+}
+
+/// The value transfers in an `eth_getBlockByNumber` result fetched with
+/// full transactions
+fn ether_transfers(block: &Value) -> Result<Vec<Transfer>, String> {
+    if block.is_null() {
+        return Err("Block not found".to_string());
+    }
+    let timestamp = block_number(&block["timestamp"])?;
+    let mut transfers = Vec::new();
+    for tx in block["transactions"].as_array().ok_or_else(|| "Block has no transactions".to_string())? {
+        // Contract creations have no recipient
+        let to = match tx["to"].as_str() {
+            Some(t) => t.to_lowercase(),
+            None => continue,
+        };
+        let amount = wei_to_gwei(parse_quantity(&tx["value"])?)?;
+        if amount == 0 {
+            continue;
+        }
+        let hash = tx["hash"].as_str().ok_or_else(|| format!("Transaction without a hash {}", tx))?;
+        transfers.push(Transfer { hash: hash.to_string(), to, amount, timestamp });
+    }
+    Ok(transfers)
+}
+
+fn btc_to_satoshis(value: &Value) -> Result<u64, String> {
+    // Format with 8 decimals so the float never needs rounding by hand
+    let btc = value.as_f64().ok_or_else(|| format!("Unexpected amount {}", value))?;
+    if btc < 0.0 {
+        return Err(format!("Unexpected amount {}", value));
+    }
+    let text = format!("{:.8}", btc);
+    let mut parts = text.splitn(2, '.');
+    let whole = parts.next().unwrap_or("0").parse::<u64>().map_err(|e| e.to_string())?;
+    let fraction = parts.next().unwrap_or("0").parse::<u64>().map_err(|e| e.to_string())?;
+    whole
+        .checked_mul(100_000_000)
+        .and_then(|w| w.checked_add(fraction))
+        .ok_or_else(|| format!("Amount too large {}", value))
+}
+
+fn wei_to_gwei(wei: u128) -> Result<u64, String> {
+    let gwei = wei / 1_000_000_000;
+    if gwei > u64::max_value() as u128 {
+        return Err(format!("Amount too large {}", wei));
+    }
+    Ok(gwei as u64)
+}
+
+fn parse_quantity(value: &Value) -> Result<u128, String> {
+    let hex = value
+        .as_str()
+        .and_then(|s| s.strip_prefix("0x"))
+        .ok_or_else(|| format!("Unexpected quantity {}", value))?;
+    u128::from_str_radix(hex, 16).map_err(|e| e.to_string())
+}
+
+fn block_number(value: &Value) -> Result<u64, String> {
+    u64::try_from(parse_quantity(value)?).map_err(|e| e.to_string())
+}
+
+/// Minimal JSON-RPC client for bitcoind and geth
+struct JsonRpc {
+    node: NodeRpc,
+    version: &'static str,
+}
+
+impl JsonRpc {
+    fn new(node: NodeRpc, version: &'static str) -> Self {
+        Self { node, version }
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, String> {
+        let body = json!({
+            "jsonrpc": self.version,
+            "id": "token-website",
+            "method": method,
+            "params": params,
+        });
+        let mut builder = Request::post(&self.node.url).header("Content-Type", "application/json");
+        if let Some(ref user) = self.node.user {
+            let credentials = format!("{}:{}", user, self.node.password.clone().unwrap_or_default());
+            builder = builder.header("Authorization", format!("Basic {}", base64::encode(&credentials)));
+        }
+        let mut response = builder
+            .timeout(Duration::from_secs(30))
+            .body(body.to_string())
+            .map_err(|e| format!("{:?}", e))?
+            .send()
+            .map_err(|e| format!("{:?}", e))?;
+        let text = response.text().map_err(|e| format!("{:?}", e))?;
+        let reply: Value = serde_json::from_str(&text).map_err(|e| format!("{}: {}", e, text))?;
+        match reply.get("error") {
+            Some(e) if !e.is_null() => Err(format!("{} failed: {}", method, e)),
+            _ => Ok(reply.get("result").cloned().unwrap_or(Value::Null)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::PaymentMethod;
+
+    fn order(amount_due: u64) -> Order {
+        Order {
+            id: "order".to_string(),
+            application_id: "application".to_string(),
+            created: 100,
+            tokens: 1,
+            payment_method: PaymentMethod::Ether,
+            amount_due,
+            currency: "ETH".to_string(),
+            state: OrderState::Pending,
+            deposit: None,
+            amount_received: 0,
+            amount_seen: 0,
+            transactions: Vec::new(),
+            payment_reference: None,
+            notes: Vec::new(),
+            authorized_at: None,
+            test: false,
+        }
+    }
+
+    #[test]
+    fn payments_move_orders_forward_only() {
+        let mut o = order(100);
+        record_payment(&mut o, 0, 40);
+        assert_eq!((o.state, o.amount_received, o.amount_seen), (OrderState::Pending, 0, 40));
+        record_payment(&mut o, 40, 40);
+        assert_eq!(o.state, OrderState::PartiallyPaid);
+        record_payment(&mut o, 100, 100);
+        assert_eq!(o.state, OrderState::Paid);
+        // A node lagging behind or a swept address does not unpay the order
+        record_payment(&mut o, 0, 0);
+        assert_eq!((o.state, o.amount_received, o.amount_seen), (OrderState::Paid, 100, 100));
+        record_payment(&mut o, 101, 101);
+        assert_eq!(o.state, OrderState::Overpaid);
+
+        let mut o = order(100);
+        credit_transfer(&mut o, "0xa", 60);
+        credit_transfer(&mut o, "0xa", 60);
+        assert_eq!((o.state, o.amount_received), (OrderState::PartiallyPaid, 60));
+        credit_transfer(&mut o, "0xb", 40);
+        assert_eq!((o.state, o.amount_received, o.transactions.len()), (OrderState::Paid, 100, 2));
+    }
+
+    #[test]
+    fn paid_orders_are_watched_for_overpayments() {
+        let store = Store::temporary().unwrap();
+        let mut o = order(100);
+        credit_transfer(&mut o, "0xa", 100);
+        assert_eq!(o.state, OrderState::Paid);
+        store.create_order(&o).unwrap();
+
+        let watched = |since| store.watched_orders(since).unwrap().iter().map(|o| o.id.clone()).collect::<Vec<_>>();
+        assert_eq!(watched(o.created), vec![o.id.clone()]);
+        assert!(watched(o.created + 1).is_empty());
+
+        // A second transfer after the order is paid
+        let o = store.update_order(&o.id, |o| credit_transfer(o, "0xb", 5)).unwrap();
+        assert_eq!((o.state, o.amount_received), (OrderState::Overpaid, 105));
+        assert_eq!(watched(o.created), vec![o.id.clone()]);
+    }
+
+    #[test]
+    fn node_amounts_are_parsed() {
+        assert_eq!(btc_to_satoshis(&json!(0.1)), Ok(10_000_000));
+        assert_eq!(btc_to_satoshis(&json!(0.00000001)), Ok(1));
+        assert_eq!(btc_to_satoshis(&json!(21_000_000.0)), Ok(2_100_000_000_000_000));
+        assert_eq!(btc_to_satoshis(&json!(0)), Ok(0));
+        assert!(btc_to_satoshis(&json!(-1.0)).is_err());
+        assert!(btc_to_satoshis(&json!("0.1")).is_err());
+
+        assert_eq!(parse_quantity(&json!("0x0")), Ok(0));
+        assert_eq!(parse_quantity(&json!("0xde0b6b3a7640000")), Ok(1_000_000_000_000_000_000));
+        assert!(parse_quantity(&json!("de0b6b3a7640000")).is_err());
+        assert!(parse_quantity(&json!("0x")).is_err());
+        assert!(parse_quantity(&json!(16)).is_err());
+        assert!(block_number(&json!("0x10000000000000000")).is_err());
+        assert_eq!(wei_to_gwei(1_999_999_999), Ok(1));
+    }
+
+    #[test]
+    fn transfers_are_read_from_blocks() {
+        let block = json!({
+            "number": "0x10",
+            "timestamp": "0x64",
+            "transactions": [
+                { "hash": "0x1", "to": "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf", "value": "0xde0b6b3a7640000" },
+                { "hash": "0x2", "to": null, "value": "0x1" },
+                { "hash": "0x3", "to": "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf", "value": "0x0" }
+            ]
+        });
+        assert_eq!(
+            ether_transfers(&block).unwrap(),
+            vec![Transfer {
+                hash: "0x1".to_string(),
+                to: "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf".to_string(),
+                amount: 1_000_000_000,
+                timestamp: 100,
+            }]
+        );
+        assert!(ether_transfers(&Value::Null).is_err());
+    }
+}